@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// How many backups are kept for each file, if not configured
+pub const DEFAULT_BACKUPS: usize = 5;
+
+/// Directory where backups of a file are kept
+///
+/// Each file gets a directory named after its (escaped) absolute path, containing one backup per
+/// timestamp.
+///
+/// * `base_dir` - flavours data directory
+/// * `file` - Backed up file
+fn file_backup_dir(base_dir: &Path, file: &Path) -> PathBuf {
+    let escaped = file
+        .to_string_lossy()
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    base_dir.join("backups").join(escaped)
+}
+
+/// Get back a file path from its backup directory name
+///
+/// * `name` - Backup directory name
+fn unescape(name: &str) -> PathBuf {
+    PathBuf::from(name.replace("%2F", "/").replace("%25", "%"))
+}
+
+/// Timestamps of all backups of a file, oldest first
+///
+/// * `dir` - Backup directory of the file
+fn timestamps(dir: &Path) -> Result<Vec<u64>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut timestamps = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Couldn't read {:?}", dir))? {
+        if let Some(Ok(timestamp)) = entry?.file_name().to_str().map(str::parse) {
+            timestamps.push(timestamp);
+        }
+    }
+    timestamps.sort_unstable();
+    Ok(timestamps)
+}
+
+/// Save a backup of a file, dropping the oldest backups if there are more than `keep`
+///
+/// Existing backups are never overwritten.
+///
+/// * `base_dir` - flavours data directory
+/// * `file` - File being backed up
+/// * `content` - File content
+/// * `timestamp` - When the backup was taken
+/// * `keep` - How many backups to keep for the file
+pub fn save(base_dir: &Path, file: &Path, content: &[u8], timestamp: u64, keep: usize) -> Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let dir = file_backup_dir(base_dir, file);
+    fs::create_dir_all(&dir).with_context(|| format!("Couldn't create directory {:?}", dir))?;
+    let backup_file = dir.join(timestamp.to_string());
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&backup_file)
+        .and_then(|mut backup| backup.write_all(content))
+        .with_context(|| format!("Couldn't write backup {:?}", backup_file))?;
+
+    let timestamps = timestamps(&dir)?;
+    let overflow = timestamps.len().saturating_sub(keep);
+    for timestamp in &timestamps[..overflow] {
+        fs::remove_file(dir.join(timestamp.to_string()))?;
+    }
+    Ok(())
+}
+
+/// List all backed up files, along with their backup timestamps (oldest first)
+///
+/// * `base_dir` - flavours data directory
+pub fn list(base_dir: &Path) -> Result<Vec<(PathBuf, Vec<u64>)>> {
+    let dir = base_dir.join("backups");
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("Couldn't read {:?}", dir))? {
+        let entry = entry?;
+        let file = unescape(&entry.file_name().to_string_lossy());
+        let timestamps = timestamps(&entry.path())?;
+        if !timestamps.is_empty() {
+            files.push((file, timestamps));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Find the latest backup of a file that is not newer than `at`
+///
+/// * `base_dir` - flavours data directory
+/// * `file` - Backed up file
+/// * `at` - Timestamp to look for, defaults to the latest backup
+pub fn find(base_dir: &Path, file: &Path, at: Option<u64>) -> Result<(u64, PathBuf)> {
+    let dir = file_backup_dir(base_dir, file);
+    let timestamp = timestamps(&dir)?
+        .into_iter()
+        .rev()
+        .find(|timestamp| at.map_or(true, |at| *timestamp <= at))
+        .ok_or_else(|| match at {
+            Some(at) => anyhow!("No backup of {:?} taken at or before {}", file, at),
+            None => anyhow!("No backup of {:?} found", file),
+        })?;
+    Ok((timestamp, dir.join(timestamp.to_string())))
+}
@@ -46,6 +46,8 @@ pub fn build_cli() -> App<'static> {
         .subcommand(
             App::new("current")
                 .about("Prints last applied scheme name")
+                // 'c' would otherwise be ambiguous with 'config' when inferring subcommands
+                .alias("c")
                 .setting(AppSettings::UnifiedHelpMessage)
                 .setting(AppSettings::DeriveDisplayOrder)
                 .setting(AppSettings::DisableHelpSubcommand)
@@ -83,6 +85,9 @@ pub fn build_cli() -> App<'static> {
         .subcommand(
             App::new("info")
                 .about("Shows scheme colors for all schemes matching pattern. Optionally uses truecolor")
+                // 'i' and 'in' would otherwise be ambiguous with 'init' when inferring subcommands
+                .alias("i")
+                .alias("in")
                 .setting(AppSettings::UnifiedHelpMessage)
                 .setting(AppSettings::DeriveDisplayOrder)
                 .setting(AppSettings::DisableHelpSubcommand)
@@ -182,10 +187,188 @@ pub fn build_cli() -> App<'static> {
                     .about("Reads scheme from stdin instead of from flavours directory.")
                     .long("stdin")
                 )
+                .arg(
+                    Arg::new("dry-run")
+                    .about("Prints a diff of what would change on each file, without writing files, running hooks or updating the current scheme.")
+                    .long("dry-run")
+                    .short('n')
+                )
+                .arg(
+                    Arg::new("force")
+                    .about("Writes files and runs their hooks even if their content didn't change")
+                    .long("force")
+                    .short('f')
+                )
+                .arg(
+                    Arg::new("only")
+                    .about("Only apply items matching this glob pattern (by item id, template or file). Can be used multiple times.")
+                    .long("only")
+                    .value_name("ITEM")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_hint(ValueHint::Other)
+                )
+                .arg(
+                    Arg::new("skip")
+                    .about("Don't apply items matching this glob pattern (by item id, template or file). Can be used multiple times.")
+                    .long("skip")
+                    .value_name("ITEM")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_hint(ValueHint::Other)
+                )
+                .arg(
+                    Arg::new("profile")
+                    .about("Apply items from this profile (defined on the config file), instead of the default one.")
+                    .long("profile")
+                    .short('p')
+                    .value_name("PROFILE")
+                    .takes_value(true)
+                    .value_hint(ValueHint::Other)
+                )
+        )
+        .subcommand(
+            App::new("history")
+                .about("Lists previously applied schemes, oldest first")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+        )
+        .subcommand(
+            App::new("undo")
+                .about("Applies the previously applied scheme again, according to user configuration")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+                .arg(
+                    Arg::new("light")
+                    .about("Skip running heavier hooks (entries marked 'light=false')")
+                    .long("light")
+                    .short('l')
+                )
+                .arg(
+                    Arg::new("dry-run")
+                    .about("Prints a diff of what would change on each file, without writing files, running hooks or updating the current scheme.")
+                    .long("dry-run")
+                    .short('n')
+                )
+        )
+        .subcommand(
+            App::new("watch")
+                .about("Applies the current scheme again whenever configuration, templates or schemes change")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+                .arg(
+                    Arg::new("light")
+                    .about("Skip running heavier hooks (entries marked 'light=false')")
+                    .long("light")
+                    .short('l')
+                )
+                .arg(
+                    Arg::new("profile")
+                    .about("Apply items from this profile (defined on the config file), instead of the default one.")
+                    .long("profile")
+                    .short('p')
+                    .value_name("PROFILE")
+                    .takes_value(true)
+                    .value_hint(ValueHint::Other)
+                )
+        )
+        .subcommand(
+            App::new("restore")
+                .about("Restores files from the backups taken before apply overwrote them")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+                .arg(
+                    Arg::new("file")
+                    .about("File to restore.")
+                    .value_hint(ValueHint::FilePath)
+                    .conflicts_with("all")
+                )
+                .arg(
+                    Arg::new("all")
+                    .about("Restore all backed up files.")
+                    .long("all")
+                    .short('a')
+                )
+                .arg(
+                    Arg::new("at")
+                    .about("Restore the latest backup taken at or before this timestamp (in milliseconds, as shown by 'history' or '--list'). Backups taken when applying have the same timestamp as the history entry. If omitted, restores the latest backup.")
+                    .long("at")
+                    .value_name("timestamp")
+                    .takes_value(true)
+                    .value_hint(ValueHint::Other)
+                )
+                .arg(
+                    Arg::new("list")
+                    .about("List backed up files and their backup timestamps, instead of restoring.")
+                    .long("list")
+                    .conflicts_with_all(&["file", "all", "at"])
+                )
+        )
+        .subcommand(
+            App::new("config")
+                .about("Inspects configuration")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("show")
+                        .about("Prints the effective configuration, with user configuration layered over the system one (/etc/flavours.conf)")
+                        .setting(AppSettings::UnifiedHelpMessage)
+                        .setting(AppSettings::DeriveDisplayOrder)
+                        .setting(AppSettings::DisableHelpSubcommand)
+                        .setting(AppSettings::DisableVersionFlag)
+                        .setting(AppSettings::ColoredHelp)
+                        .arg(
+                            Arg::new("layers")
+                            .about("Also print each configuration file it's made of, lowest layer first")
+                            .long("layers")
+                        )
+                )
+                .subcommand(
+                    App::new("check")
+                        .about("Checks configuration for unknown keys, missing templates, and files without delimiters")
+                        .setting(AppSettings::UnifiedHelpMessage)
+                        .setting(AppSettings::DeriveDisplayOrder)
+                        .setting(AppSettings::DisableHelpSubcommand)
+                        .setting(AppSettings::DisableVersionFlag)
+                        .setting(AppSettings::ColoredHelp)
+                )
+        )
+        .subcommand(
+            App::new("init")
+                .about("Creates a configuration, with items for known applications found on your system")
+                .setting(AppSettings::UnifiedHelpMessage)
+                .setting(AppSettings::DeriveDisplayOrder)
+                .setting(AppSettings::DisableHelpSubcommand)
+                .setting(AppSettings::DisableVersionFlag)
+                .setting(AppSettings::ColoredHelp)
+                .arg(
+                    Arg::new("force")
+                    .about("Overwrite the configuration file if it already exists")
+                    .long("force")
+                    .short('f')
+                )
         )
         .subcommand(
             App::new("update")
                 .about("Downloads schemes, templates, or updates their lists (from repos specified in sources.yml)")
+                // 'u' would otherwise be ambiguous with 'undo' when inferring subcommands
+                .alias("u")
                 .setting(AppSettings::UnifiedHelpMessage)
                 .setting(AppSettings::DeriveDisplayOrder)
                 .setting(AppSettings::DisableHelpSubcommand)
@@ -218,5 +401,14 @@ pub fn build_cli() -> App<'static> {
                     .required(true)
                     .value_hint(ValueHint::FilePath)
                 )
+                .arg(
+                    Arg::new("var")
+                    .about("Set a template variable (e.g. --var font-family=monospace). Can be used multiple times.")
+                    .long("var")
+                    .value_name("KEY=VALUE")
+                    .takes_value(true)
+                    .multiple_occurrences(true)
+                    .value_hint(ValueHint::Other)
+                )
         )
 }
@@ -1,37 +1,226 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use glob::{glob, Pattern};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// System wide configuration, which user configuration is layered over
+pub const SYSTEM_CONFIG: &str = "/etc/flavours.conf";
 
 /// Structure for configuration
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Config {
+    pub include: Option<Vec<String>>,
     pub shell: Option<String>,
+    pub backups: Option<usize>,
+    pub fail_on_hook_error: Option<bool>,
+    pub hook_timeout: Option<u64>,
+    pub hooks: Option<HookMode>,
+    pub pre_hook: Option<String>,
+    pub pre_hook_light: Option<bool>,
+    pub post_hook: Option<String>,
+    pub post_hook_light: Option<bool>,
     pub schemes: Option<String>,
     pub templates: Option<String>,
     pub extra_scheme: Option<Vec<ExtraSource>>,
     pub extra_template: Option<Vec<ExtraSource>>,
     pub item: Option<Vec<ConfigItem>>,
     pub items: Option<Vec<ConfigItem>>,
+    pub default_profile: Option<String>,
+    pub profiles: Option<BTreeMap<String, Profile>>,
+    pub variables: Option<BTreeMap<String, toml::Value>>,
+}
+
+/// A configuration file, as one of the layers making up the effective configuration
+#[derive(Debug)]
+pub struct Layer {
+    /// Where it was read from
+    pub path: PathBuf,
+    /// Whether it is part of the system configuration, instead of the user's
+    pub system: bool,
+    /// Configuration it contains
+    pub config: Config,
+}
+
+/// Structure for named sets of items
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub items: Vec<ProfileItem>,
+}
+
+/// Profile entries, either referring to a top level item by id, or defining a new one
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ProfileItem {
+    Id(String),
+    Item(Box<ConfigItem>),
 }
 
 /// Structure for configuration extra sources
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExtraSource{
     pub name: String,
     pub source: String,
 }
 
+/// How item hooks are ran
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookMode {
+    /// Run hooks at the same time, as soon as the ones they come after are done
+    Parallel,
+    /// Run one hook at a time, in order
+    Sequential,
+}
+
 /// Structure for configuration apply items
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigItem {
+    pub id: Option<String>,
     pub file: String,
-    pub template: String,
+    pub template: Option<String>,
     pub subtemplate: Option<String>,
+    pub inline: Option<String>,
+    pub scheme: Option<String>,
     pub hook: Option<String>,
+    pub timeout: Option<u64>,
+    pub after: Option<Vec<String>>,
     pub rewrite: Option<bool>,
     pub light: Option<bool>,
     pub start: Option<String>,
     pub end: Option<String>,
+    pub block: Option<String>,
+    pub insert: Option<InsertMode>,
+    pub indent: Option<bool>,
+    pub keep_line_endings: Option<bool>,
+    pub mode: Option<String>,
+    pub when: Option<Condition>,
+    pub variables: Option<BTreeMap<String, toml::Value>>,
+}
+
+/// What to do when a file is missing an item's delimiters (or doesn't exist at all)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum InsertMode {
+    /// Add them (and create the file, if needed) at the end
+    Append,
+    /// Add them (and create the file, if needed) at the start
+    Prepend,
+    /// Report it as an error
+    Error,
+}
+
+/// Conditions an item needs to meet to be applied, all of them if more than one is set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Condition {
+    /// Whether the item's scheme is dark or light
+    pub variant: Option<Variant>,
+    /// Hostname glob
+    pub hostname: Option<String>,
+    /// Environment variables that need to be set, along with a glob their value must match
+    pub env: Option<BTreeMap<String, String>>,
+    /// Path that needs to exist, supports tilde and env var expansion
+    pub exists: Option<String>,
+}
+
+/// Whether a scheme has a dark or light background
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    Dark,
+    Light,
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variant::Dark => write!(f, "dark"),
+            Variant::Light => write!(f, "light"),
+        }
+    }
+}
+
+/// Where an item's template comes from
+pub enum TemplateSource<'a> {
+    /// An installed template, by name
+    Named {
+        template: &'a str,
+        subtemplate: &'a str,
+    },
+    /// Mustache text, set on the item itself
+    Inline(&'a str),
+}
+
+impl ConfigItem {
+    /// Get the item's template, which is either a template (and subtemplate) or inline
+    pub fn template_source(&self) -> Result<TemplateSource<'_>> {
+        match (&self.template, &self.inline, &self.subtemplate) {
+            (Some(template), None, subtemplate) => Ok(TemplateSource::Named {
+                template,
+                subtemplate: subtemplate.as_deref().unwrap_or("default"),
+            }),
+            (None, Some(inline), None) => Ok(TemplateSource::Inline(inline)),
+            (None, Some(_), Some(_)) => Err(anyhow!(
+                "Item {:?} has an inline template, so it can't have a subtemplate",
+                self.file
+            )),
+            (Some(_), Some(_), _) => Err(anyhow!(
+                "Item {:?} has both a template and an inline template, only one is allowed",
+                self.file
+            )),
+            (None, None, _) => Err(anyhow!(
+                "Item {:?} needs either a template or an inline template",
+                self.file
+            )),
+        }
+    }
+
+    /// Line where the replaced section starts, as written when inserting it
+    pub fn start_line(&self) -> String {
+        match (&self.start, &self.block) {
+            (Some(start), _) => start.clone(),
+            (None, Some(block)) => format!("# Start flavours: {}", block),
+            (None, None) => String::from("# Start flavours"),
+        }
+    }
+
+    /// Line where the replaced section ends, as written when inserting it
+    pub fn end_line(&self) -> String {
+        match (&self.end, &self.block) {
+            (Some(end), _) => end.clone(),
+            (None, Some(block)) => format!("# End flavours: {}", block),
+            (None, None) => String::from("# End flavours"),
+        }
+    }
+
+    /// Line where the replaced section starts, trimmed and lowercased (as it's matched)
+    pub fn start_delimiter(&self) -> String {
+        self.start_line().trim().to_lowercase()
+    }
+
+    /// Line where the replaced section ends, trimmed and lowercased (as it's matched)
+    pub fn end_delimiter(&self) -> String {
+        self.end_line().trim().to_lowercase()
+    }
+
+    /// File mode (permission bits) to set after writing, parsed from octal (e.g. "0755")
+    pub fn mode(&self) -> Result<Option<u32>> {
+        let mode = match &self.mode {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        let digits = mode.trim_start_matches("0o");
+        match u32::from_str_radix(digits, 8) {
+            Ok(parsed) if !digits.is_empty() && parsed <= 0o7777 => Ok(Some(parsed)),
+            _ => Err(anyhow!(
+                "Item {:?} has an invalid mode '{}', it should be octal (e.g. \"0755\")",
+                self.file,
+                mode
+            )),
+        }
+    }
 }
 
 impl Config {
@@ -40,4 +229,475 @@ impl Config {
         toml::from_str(contents)
             .context(format!("Couldn't parse flavours configuration file ({:?}). Check if it's syntatically correct", path))
     }
+
+    /// Read and parse a configuration file
+    ///
+    /// * `path` - Configuration file path
+    fn read_file(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read configuration file {:?}.", path))?;
+        Config::read(&contents, path)
+    }
+
+    /// Load every configuration layer, lowest first
+    ///
+    /// The system configuration (`/etc/flavours.conf`) and the files it includes come first,
+    /// followed by the user configuration and the files it includes (`include` entries, then
+    /// the `config.d` directory next to it). Missing configuration files are skipped.
+    ///
+    /// * `path` - User configuration file path
+    pub fn load_layers(path: &Path) -> Result<Vec<Layer>> {
+        let mut layers = Vec::new();
+        for (file, system) in [(Path::new(SYSTEM_CONFIG), true), (path, false)] {
+            if !file.is_file() {
+                continue;
+            }
+            let config = Config::read_file(file)?;
+            let fragment_files = config.fragment_files(file, !system)?;
+            layers.push(Layer {
+                path: file.to_path_buf(),
+                system,
+                config,
+            });
+            for fragment_path in fragment_files {
+                let fragment = Config::read_file(&fragment_path)?;
+                if fragment.include.is_some() {
+                    return Err(anyhow!(
+                        "Included configuration files can't include other files ({:?})",
+                        fragment_path
+                    ));
+                }
+                layers.push(Layer {
+                    path: fragment_path,
+                    system,
+                    config: fragment,
+                });
+            }
+        }
+        Ok(layers)
+    }
+
+    /// Load the effective configuration, with the user configuration layered over the system one
+    ///
+    /// * `path` - User configuration file path
+    pub fn load(path: &Path) -> Result<Config> {
+        Ok(Config::flatten(Config::load_layers(path)?))
+    }
+
+    /// Combine configuration layers (lowest first) into the effective configuration
+    ///
+    /// * `layers` - Configuration layers
+    pub fn flatten(layers: Vec<Layer>) -> Config {
+        let mut system = Config::default();
+        let mut user = Config::default();
+        for layer in layers {
+            if layer.system {
+                system.merge(layer.config);
+            } else {
+                user.merge(layer.config);
+            }
+        }
+        user.layer_over(system)
+    }
+
+    /// Layer this configuration over a lower one (e.g. user over system)
+    ///
+    /// Lower items come first, except the ones this configuration overrides by having an
+    /// item with the same id. Everything else is merged as in `merge`.
+    ///
+    /// * `lower` - Lower configuration
+    pub fn layer_over(mut self, mut lower: Config) -> Config {
+        let ids: HashSet<String> = self
+            .items
+            .iter()
+            .chain(self.item.iter())
+            .flatten()
+            .filter_map(|item| item.id.clone())
+            .collect();
+        let overridden = |item: &ConfigItem| item.id.as_ref().map_or(false, |id| ids.contains(id));
+
+        for (own, lower) in [(&mut self.items, &mut lower.items), (&mut self.item, &mut lower.item)] {
+            if let Some(mut items) = lower.take() {
+                items.retain(|item| !overridden(item));
+                items.extend(own.take().unwrap_or_default());
+                *own = Some(items);
+            }
+        }
+
+        self.merge(lower);
+        self
+    }
+
+    /// Files to be merged into this configuration: `include` entries, then `config.d/*.toml`
+    ///
+    /// * `path` - Path of this configuration file, relative includes are resolved from its directory
+    /// * `drop_in` - Also use the `config.d` directory
+    fn fragment_files(&self, path: &Path, drop_in: bool) -> Result<Vec<PathBuf>> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut patterns = Vec::new();
+        for include in self.include.iter().flatten() {
+            let include = shellexpand::full(include)?.to_string();
+            patterns.push((dir.join(&include), Pattern::escape(&include) == include));
+        }
+        if drop_in {
+            patterns.push((dir.join("config.d").join("*.toml"), false));
+        }
+
+        let mut files: Vec<PathBuf> = Vec::new();
+        for (pattern, literal) in patterns {
+            let pattern = pattern.to_string_lossy();
+            let mut matches = glob(&pattern)
+                .with_context(|| format!("Invalid include pattern '{}'", pattern))?
+                .collect::<Result<Vec<_>, _>>()?;
+            if literal && matches.is_empty() {
+                return Err(anyhow!("Included configuration file '{}' doesn't exist", pattern));
+            }
+            matches.sort();
+            for file in matches {
+                let file = file.canonicalize().unwrap_or(file);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Merge another configuration into this one
+    ///
+    /// Lists (items, extra sources) are appended, profiles and variables are added if there isn't
+    /// one with the same name yet, and single value settings are only used if not already set.
+    ///
+    /// * `other` - Configuration to merge
+    pub fn merge(&mut self, other: Config) {
+        fn append<T>(list: &mut Option<Vec<T>>, other: Option<Vec<T>>) {
+            if let Some(other) = other {
+                list.get_or_insert_with(Vec::new).extend(other);
+            }
+        }
+        append(&mut self.items, other.items);
+        append(&mut self.item, other.item);
+        append(&mut self.extra_scheme, other.extra_scheme);
+        append(&mut self.extra_template, other.extra_template);
+
+        if let Some(profiles) = other.profiles {
+            let own_profiles = self.profiles.get_or_insert_with(BTreeMap::new);
+            for (name, profile) in profiles {
+                own_profiles.entry(name).or_insert(profile);
+            }
+        }
+        if let Some(variables) = other.variables {
+            let own_variables = self.variables.get_or_insert_with(BTreeMap::new);
+            for (name, value) in variables {
+                own_variables.entry(name).or_insert(value);
+            }
+        }
+
+        self.shell = self.shell.take().or(other.shell);
+        self.backups = self.backups.or(other.backups);
+        self.fail_on_hook_error = self.fail_on_hook_error.or(other.fail_on_hook_error);
+        self.hook_timeout = self.hook_timeout.or(other.hook_timeout);
+        self.hooks = self.hooks.or(other.hooks);
+        self.pre_hook = self.pre_hook.take().or(other.pre_hook);
+        self.pre_hook_light = self.pre_hook_light.or(other.pre_hook_light);
+        self.post_hook = self.post_hook.take().or(other.post_hook);
+        self.post_hook_light = self.post_hook_light.or(other.post_hook_light);
+        self.schemes = self.schemes.take().or(other.schemes);
+        self.templates = self.templates.take().or(other.templates);
+        self.default_profile = self.default_profile.take().or(other.default_profile);
+    }
+
+    /// Template variables for an item: the global ones, overridden by the item's own
+    ///
+    /// * `item` - Item being built
+    pub fn variables(&self, item: &ConfigItem) -> BTreeMap<String, String> {
+        self.variables
+            .iter()
+            .chain(item.variables.iter())
+            .flatten()
+            .map(|(name, value)| {
+                let value = match value {
+                    toml::Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                (name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Items to apply
+    ///
+    /// Uses the given profile (or `default_profile`, if set), otherwise all top level items.
+    ///
+    /// * `profile` - Profile name
+    pub fn items(&self, profile: Option<&str>) -> Result<Vec<ConfigItem>> {
+        let mut items = self.items.clone().unwrap_or_default();
+        items.extend(self.item.iter().flatten().cloned());
+
+        let name = match profile.or(self.default_profile.as_deref()) {
+            Some(name) => name,
+            None => return Ok(items),
+        };
+        let profile = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(name))
+            .ok_or_else(|| anyhow!("Profile '{}' not found on config file", name))?;
+
+        let mut profile_items = profile
+            .items
+            .iter()
+            .map(|entry| match entry {
+                ProfileItem::Item(item) => Ok(item.as_ref().clone()),
+                ProfileItem::Id(id) => items
+                    .iter()
+                    .find(|item| item.id.as_deref() == Some(id))
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Profile '{}' refers to item '{}', but no item has that id",
+                            name,
+                            id
+                        )
+                    }),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Items left out of the profile aren't applied, so there is nothing to wait for. Unknown
+        // ids are kept, so they are still reported.
+        let configured: HashSet<&str> = items
+            .iter()
+            .chain(self.profile_items())
+            .filter_map(|item| item.id.as_deref())
+            .collect();
+        let included: HashSet<String> = profile_items
+            .iter()
+            .filter_map(|item| item.id.clone())
+            .collect();
+        for item in &mut profile_items {
+            if let Some(after) = &mut item.after {
+                after.retain(|id| included.contains(id) || !configured.contains(id.as_str()));
+            }
+        }
+        Ok(profile_items)
+    }
+
+    /// Items defined inside profiles (instead of referred to by id)
+    pub fn profile_items(&self) -> impl Iterator<Item = &ConfigItem> {
+        self.profiles
+            .iter()
+            .flatten()
+            .flat_map(|(_, profile)| &profile.items)
+            .filter_map(|entry| match entry {
+                ProfileItem::Item(item) => Some(item.as_ref()),
+                ProfileItem::Id(_) => None,
+            })
+    }
+}
+
+/// Resolve items `after` references into indexes of the items they depend on
+///
+/// Errors out on duplicated ids, unknown ids, and dependency cycles.
+///
+/// * `items` - Configured items
+pub fn item_dependencies(items: &[ConfigItem]) -> Result<Vec<Vec<usize>>> {
+    let mut ids = HashMap::new();
+    for (index, item) in items.iter().enumerate() {
+        if let Some(id) = &item.id {
+            if ids.insert(id.as_str(), index).is_some() {
+                return Err(anyhow!("Item id '{}' is used more than once", id));
+            }
+        }
+    }
+
+    let dependencies = items
+        .iter()
+        .map(|item| {
+            item.after
+                .iter()
+                .flatten()
+                .map(|id| {
+                    ids.get(id.as_str()).copied().ok_or_else(|| {
+                        anyhow!("Item {:?} comes after '{}', but no item has that id", item.file, id)
+                    })
+                })
+                .collect::<Result<Vec<usize>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Repeatedly take out items whose dependencies were all taken out, anything left is in a cycle
+    let mut resolved = vec![false; items.len()];
+    let mut progressed = true;
+    while progressed {
+        progressed = false;
+        for (index, item_dependencies) in dependencies.iter().enumerate() {
+            if !resolved[index] && item_dependencies.iter().all(|&dependency| resolved[dependency]) {
+                resolved[index] = true;
+                progressed = true;
+            }
+        }
+    }
+    let cycle: Vec<String> = items
+        .iter()
+        .zip(&resolved)
+        .filter(|(_, &resolved)| !resolved)
+        .map(|(item, _)| item.id.clone().unwrap_or_else(|| item.file.clone()))
+        .collect();
+    if !cycle.is_empty() {
+        return Err(anyhow!(
+            "Items can't come after each other in a cycle: {}",
+            cycle.join(", ")
+        ));
+    }
+
+    Ok(dependencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a configuration, as written on a file
+    fn config(contents: &str) -> Config {
+        Config::read(contents, Path::new("config.toml")).unwrap()
+    }
+
+    #[test]
+    fn dependencies_point_to_items() {
+        let items = config(
+            r#"
+            [[items]]
+            id = "a"
+            file = "a.conf"
+            template = "a"
+            after = ["b"]
+
+            [[items]]
+            id = "b"
+            file = "b.conf"
+            template = "b"
+            "#,
+        )
+        .items
+        .unwrap();
+        assert_eq!(item_dependencies(&items).unwrap(), vec![vec![1], vec![]]);
+    }
+
+    #[test]
+    fn dependency_cycles_are_errors() {
+        let items = config(
+            r#"
+            [[items]]
+            id = "a"
+            file = "a.conf"
+            template = "a"
+            after = ["b"]
+
+            [[items]]
+            id = "b"
+            file = "b.conf"
+            template = "b"
+            after = ["a"]
+
+            [[items]]
+            file = "c.conf"
+            template = "c"
+            after = ["a"]
+            "#,
+        )
+        .items
+        .unwrap();
+        let error = item_dependencies(&items).unwrap_err().to_string();
+        assert_eq!(error, "Items can't come after each other in a cycle: a, b, c.conf");
+    }
+
+    #[test]
+    fn profiles_ignore_items_left_out() {
+        let config = config(
+            r#"
+            default_profile = "p"
+
+            [[items]]
+            id = "a"
+            file = "a.conf"
+            template = "a"
+            after = ["b"]
+
+            [[items]]
+            id = "b"
+            file = "b.conf"
+            template = "b"
+
+            [profiles.p]
+            items = ["a", { file = "c.conf", template = "c", after = ["a", "typo"] }]
+            "#,
+        );
+        let items = config.items(None).unwrap();
+        assert_eq!(items[0].after, Some(vec![]));
+        assert_eq!(items[1].after, Some(vec!["a".into(), "typo".into()]));
+    }
+
+    #[test]
+    fn user_items_override_system_items_by_id() {
+        let system = config(
+            r#"
+            shell = "bash -c '{}'"
+            backups = 3
+
+            [[items]]
+            id = "kitty"
+            file = "system-kitty.conf"
+            template = "kitty"
+
+            [[items]]
+            file = "system.conf"
+            template = "system"
+            "#,
+        );
+        let user = config(
+            r#"
+            backups = 10
+
+            [[items]]
+            id = "kitty"
+            file = "kitty.conf"
+            template = "kitty"
+
+            [[items]]
+            file = "user.conf"
+            template = "user"
+            "#,
+        );
+        let layered = user.layer_over(system);
+
+        let files: Vec<&str> = layered
+            .items
+            .iter()
+            .flatten()
+            .map(|item| item.file.as_str())
+            .collect();
+        assert_eq!(files, ["system.conf", "kitty.conf", "user.conf"]);
+        assert_eq!(layered.backups, Some(10));
+        assert_eq!(layered.shell.as_deref(), Some("bash -c '{}'"));
+    }
+
+    #[test]
+    fn duplicated_ids_are_errors() {
+        let items = config(
+            r#"
+            [[items]]
+            id = "a"
+            file = "a.conf"
+            template = "a"
+
+            [[items]]
+            id = "a"
+            file = "b.conf"
+            template = "b"
+            "#,
+        )
+        .items
+        .unwrap();
+        assert!(item_dependencies(&items).is_err());
+    }
 }
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many entries are kept on the history
+const HISTORY_SIZE: usize = 100;
+
+/// Where an applied scheme came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// Picked from installed schemes using patterns
+    Pattern,
+    /// Read from stdin (e.g. piped from generate)
+    Stdin,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Pattern => write!(f, "pattern"),
+            Source::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Source> {
+        match s {
+            "pattern" => Ok(Source::Pattern),
+            "stdin" => Ok(Source::Stdin),
+            _ => Err(anyhow!("Unknown history source '{}'", s)),
+        }
+    }
+}
+
+/// Structure for history entries
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// When it was applied, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Applied scheme slug
+    pub slug: String,
+    /// Path to the applied scheme file
+    pub scheme_file: PathBuf,
+    /// Where the scheme came from
+    pub source: Source,
+}
+
+impl Entry {
+    /// Parse a tab separated history line
+    fn parse(line: &str) -> Result<Entry> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 4 {
+            return Err(anyhow!("Malformed history line '{}'", line));
+        }
+        Ok(Entry {
+            timestamp: fields[0].parse()?,
+            slug: fields[1].into(),
+            scheme_file: PathBuf::from(fields[2]),
+            source: fields[3].parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.slug,
+            self.scheme_file.display(),
+            self.source
+        )
+    }
+}
+
+/// Current time, in milliseconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Timestamp for a new apply, later than every recorded one
+///
+/// Backups and history entries are named after it, so applies in quick succession must never
+/// share one.
+///
+/// * `base_dir` - flavours data directory
+pub fn next_timestamp(base_dir: &Path) -> Result<u64> {
+    let now = now();
+    Ok(match read(base_dir)?.last() {
+        Some(last) if last.timestamp >= now => last.timestamp + 1,
+        _ => now,
+    })
+}
+
+/// Where a scheme read from stdin is kept, so it can be applied again later
+///
+/// Each scheme gets its own directory, named after the timestamp (with a `-N` suffix if more than
+/// one scheme is applied at the same time), so they never overwrite each other.
+///
+/// * `base_dir` - flavours data directory
+/// * `timestamp` - When the scheme was applied
+/// * `slug` - Scheme slug
+pub fn stdin_scheme_file(base_dir: &Path, timestamp: u64, slug: &str) -> PathBuf {
+    let stdin_dir = base_dir.join("stdin");
+    let mut dir = stdin_dir.join(timestamp.to_string());
+    let mut suffix = 1;
+    while dir.exists() {
+        dir = stdin_dir.join(format!("{}-{}", timestamp, suffix));
+        suffix += 1;
+    }
+    dir.join(format!("{}.yaml", slug))
+}
+
+/// Read all history entries, oldest first
+///
+/// * `base_dir` - flavours data directory
+pub fn read(base_dir: &Path) -> Result<Vec<Entry>> {
+    let file = base_dir.join("history");
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    fs::read_to_string(&file)
+        .with_context(|| format!("Couldn't read history file {:?}", file))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(Entry::parse)
+        .collect::<Result<_>>()
+        .with_context(|| format!("Couldn't parse history file {:?}", file))
+}
+
+/// Replace history with given entries, removing stored stdin schemes that are no longer referenced
+///
+/// * `base_dir` - flavours data directory
+/// * `entries` - History entries, oldest first
+/// * `removed` - Entries that were dropped from the history
+fn write(base_dir: &Path, entries: &[Entry], removed: &[Entry]) -> Result<()> {
+    let file = base_dir.join("history");
+    let content: String = entries
+        .iter()
+        .map(|entry| format!("{}\n", entry))
+        .collect();
+    fs::write(&file, content).with_context(|| format!("Couldn't write history file {:?}", file))?;
+
+    for entry in removed.iter().filter(|entry| entry.source == Source::Stdin) {
+        let dir = match entry.scheme_file.parent() {
+            Some(dir) => dir,
+            None => continue,
+        };
+        // Remaining entries may still use it (e.g. the same scheme applied again)
+        if !entries.iter().any(|kept| kept.scheme_file.starts_with(dir)) {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+    Ok(())
+}
+
+/// Add an entry to the end of the history, dropping the oldest ones if it's full
+///
+/// * `base_dir` - flavours data directory
+/// * `entry` - Entry to add
+pub fn append(base_dir: &Path, entry: Entry) -> Result<()> {
+    let mut entries = read(base_dir)?;
+    entries.push(entry);
+    let overflow = entries.len().saturating_sub(HISTORY_SIZE);
+    let removed: Vec<Entry> = entries.drain(..overflow).collect();
+    write(base_dir, &entries, &removed)
+}
+
+/// Remove the last entry from the history
+///
+/// * `base_dir` - flavours data directory
+pub fn pop(base_dir: &Path) -> Result<Option<Entry>> {
+    let mut entries = read(base_dir)?;
+    let removed: Vec<Entry> = entries.pop().into_iter().collect();
+    write(base_dir, &entries, &removed)?;
+    Ok(removed.into_iter().next())
+}
@@ -0,0 +1,394 @@
+use anyhow::{Context, Result};
+use base16_color_scheme::{
+    scheme::{BaseIndex, RgbColor},
+    Scheme,
+};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::process::{self, ExitStatus};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Structure for the outcome of a hook
+#[derive(Debug)]
+pub struct HookOutput {
+    /// What the hook belongs to (e.g. item file)
+    pub label: String,
+    /// Full command that was ran, including shell
+    pub command: String,
+    /// Exit code, or none if it couldn't run or was killed by a signal
+    pub code: Option<i32>,
+    /// Why the hook failed before exiting, if it did
+    pub error: Option<String>,
+    /// Captured standard output
+    pub stdout: String,
+    /// Captured standard error
+    pub stderr: String,
+}
+
+impl HookOutput {
+    /// Did the hook run and exit successfully?
+    pub fn success(&self) -> bool {
+        self.error.is_none() && self.code == Some(0)
+    }
+
+    /// Short description of how the hook ended
+    pub fn status(&self) -> String {
+        match (&self.error, self.code) {
+            (Some(error), _) => error.clone(),
+            (None, Some(code)) => format!("exited with code {}", code),
+            (None, None) => String::from("terminated by signal"),
+        }
+    }
+
+    /// Format the hook outcome and output for logging
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let _ = writeln!(report, "[{}] $ {}", self.label, self.command);
+        let _ = writeln!(report, "{}", self.status());
+        if !self.stdout.is_empty() {
+            let _ = writeln!(report, "--- stdout\n{}", self.stdout.trim_end());
+        }
+        if !self.stderr.is_empty() {
+            let _ = writeln!(report, "--- stderr\n{}", self.stderr.trim_end());
+        }
+        report
+    }
+}
+
+/// Build the process for a hook command, already wrapped in the configured shell
+///
+/// * `full_command` - Command string to execute
+fn shell_command(full_command: &str) -> Result<process::Command> {
+    let command_vec = shell_words::split(full_command)
+        .with_context(|| format!("Couldn't parse hook '{}'", full_command))?;
+    let (program, args) = command_vec
+        .split_first()
+        .with_context(|| format!("Hook '{}' is empty", full_command))?;
+
+    let mut process = process::Command::new(program);
+    process.args(args);
+    new_process_group(&mut process);
+    Ok(process)
+}
+
+/// Make the process lead its own process group, so everything it starts can be killed along with
+/// it (a shell wrapping the hook won't kill its children)
+///
+/// * `process` - Process about to be spawned
+#[cfg(unix)]
+fn new_process_group(process: &mut process::Command) {
+    use std::os::unix::process::CommandExt;
+
+    process.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn new_process_group(_process: &mut process::Command) {}
+
+/// Kill a hook process, along with every process on its process group
+///
+/// * `child` - Hook process
+#[cfg(unix)]
+fn kill(child: &mut process::Child) -> io::Result<()> {
+    // The group id is the leader's pid, and it stays valid until the leader is waited on
+    let group = child.id() as libc::pid_t;
+    // SAFETY: killpg has no memory safety requirements
+    if unsafe { libc::killpg(group, libc::SIGKILL) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut process::Child) -> io::Result<()> {
+    child.kill()
+}
+
+/// How often to check if a hook with a timeout has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Create an anonymous temporary file to capture hook output
+///
+/// Files are used instead of pipes so hooks that leave processes running in the background don't
+/// block us (or get killed by a broken pipe once we exit).
+fn capture_file() -> Result<fs::File> {
+    // Unnamed and only accessible by us, so other users can't interfere with it or read it
+    tempfile::tempfile().context("Couldn't create temporary file to capture hook output")
+}
+
+/// Read everything captured on a file
+///
+/// * `file` - Capture file
+fn read_capture(mut file: fs::File) -> String {
+    let mut buffer = Vec::new();
+    let _ = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_end(&mut buffer));
+    String::from_utf8_lossy(&buffer).into()
+}
+
+/// Wait for a hook process to exit, killing it if it takes longer than the timeout
+///
+/// * `child` - Hook process
+/// * `timeout` - How long to wait before killing it
+fn wait(child: &mut process::Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(Some(child.wait()?)),
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            kill(child)?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Structure for a hook waiting to be ran
+#[derive(Debug, Clone)]
+pub struct Hook {
+    /// What the hook belongs to (e.g. item file), used when reporting
+    pub label: String,
+    /// Command string to execute
+    pub command: String,
+    /// Kill the hook if it runs for longer than this
+    pub timeout: Option<Duration>,
+    /// Environment variables to set for the hook
+    pub env: Vec<(String, String)>,
+}
+
+impl Hook {
+    /// Runs hook commands, capturing their output
+    ///
+    /// * `shell` - Shell command, with a '{}' placeholder for the hook command
+    /// * `verbose` - Should we be verbose?
+    pub fn run(&self, shell: &str, verbose: bool) -> HookOutput {
+        let mut output = HookOutput {
+            label: self.label.clone(),
+            command: shell.replace("{}", &self.command),
+            code: None,
+            error: None,
+            stdout: String::new(),
+            stderr: String::new(),
+        };
+        if verbose {
+            println!("running {}", output.command);
+        }
+
+        if let Err(error) = run_process(&mut output, self.timeout, &self.env) {
+            output.error = Some(format!("{:#}", error));
+        }
+        output
+    }
+}
+
+/// Run hooks, each one only after the ones it depends on are done
+///
+/// Dependencies must not have cycles (see `config::item_dependencies`). Returns outputs in the
+/// same order as the given hooks.
+///
+/// * `hooks` - Hooks to run (none if there's nothing to run for that entry), and indexes of the entries they depend on
+/// * `sequential` - Run one hook at a time, instead of in parallel
+/// * `shell` - Shell command, with a '{}' placeholder for the hook command
+/// * `verbose` - Should we be verbose?
+pub fn run_all(
+    hooks: Vec<(Option<Hook>, Vec<usize>)>,
+    sequential: bool,
+    shell: &str,
+    verbose: bool,
+) -> Vec<HookOutput> {
+    let (dependencies, mut hooks): (Vec<_>, Vec<_>) = hooks
+        .into_iter()
+        .map(|(hook, dependencies)| (dependencies, hook))
+        .unzip();
+    let mut started = vec![false; hooks.len()];
+    let mut done = vec![false; hooks.len()];
+    let mut outputs: Vec<Option<HookOutput>> = hooks.iter().map(|_| None).collect();
+
+    let (sender, receiver) = mpsc::channel();
+    let mut running = 0;
+    loop {
+        // Start everything that's ready, entries without a hook are done right away
+        let mut progressed = true;
+        while progressed {
+            progressed = false;
+            for index in 0..hooks.len() {
+                if sequential && running > 0 {
+                    break;
+                }
+                if started[index] || !dependencies[index].iter().all(|&dependency| done[dependency]) {
+                    continue;
+                }
+                started[index] = true;
+                match hooks[index].take() {
+                    Some(hook) => {
+                        running += 1;
+                        let sender = sender.clone();
+                        let shell = shell.to_string();
+                        thread::spawn(move || {
+                            let _ = sender.send((index, hook.run(&shell, verbose)));
+                        });
+                    }
+                    None => {
+                        done[index] = true;
+                        progressed = true;
+                    }
+                }
+            }
+        }
+
+        if running == 0 {
+            break;
+        }
+        match receiver.recv() {
+            Ok((index, output)) => {
+                running -= 1;
+                done[index] = true;
+                outputs[index] = Some(output);
+            }
+            Err(_) => break,
+        }
+    }
+
+    outputs.into_iter().flatten().collect()
+}
+
+/// Environment variables describing a scheme, for hooks to use
+///
+/// Sets `FLAVOURS_SCHEME_SLUG`, `FLAVOURS_SCHEME_NAME`, `FLAVOURS_SCHEME_AUTHOR`, and a
+/// `FLAVOURS_BASEXX` variable (hex, without a leading '#') for each color.
+///
+/// * `scheme` - Applied scheme
+pub fn scheme_env(scheme: &Scheme) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("FLAVOURS_SCHEME_SLUG".into(), scheme.scheme_slug().into()),
+        ("FLAVOURS_SCHEME_NAME".into(), scheme.scheme_name().into()),
+        ("FLAVOURS_SCHEME_AUTHOR".into(), scheme.scheme_author().into()),
+    ];
+    for (BaseIndex(index), RgbColor(color)) in &scheme.colors {
+        env.push((format!("FLAVOURS_BASE{:02X}", index), hex::encode(color)));
+    }
+    env
+}
+
+/// Run the hook process, filling in its outcome
+///
+/// * `output` - Hook outcome, with the full command already set
+/// * `timeout` - Kill the hook if it runs for longer than this
+/// * `env` - Environment variables to set
+fn run_process(
+    output: &mut HookOutput,
+    timeout: Option<Duration>,
+    env: &[(String, String)],
+) -> Result<()> {
+    let stdout = capture_file()?;
+    let stderr = capture_file()?;
+
+    let mut child = shell_command(&output.command)?
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(process::Stdio::null())
+        .stdout(stdout.try_clone()?)
+        .stderr(stderr.try_clone()?)
+        .spawn()
+        .with_context(|| format!("Couldn't run hook '{}'", output.command))?;
+
+    let status = wait(&mut child, timeout)
+        .with_context(|| format!("Couldn't wait for hook '{}'", output.command))?;
+
+    output.stdout = read_capture(stdout);
+    output.stderr = read_capture(stderr);
+    match (status, timeout) {
+        (Some(status), _) => output.code = status.code(),
+        (None, Some(timeout)) => {
+            output.error = Some(format!("timed out after {}s, killed", timeout.as_secs_f32()))
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHELL: &str = "sh -c '{}'";
+
+    /// Hook appending to the log file, as `$LOG`
+    fn hook(log: &std::path::Path, command: &str) -> Option<Hook> {
+        Some(Hook {
+            label: command.into(),
+            command: command.into(),
+            timeout: None,
+            env: vec![("LOG".into(), log.to_string_lossy().into())],
+        })
+    }
+
+    fn read_log(log: &std::path::Path) -> Vec<String> {
+        fs::read_to_string(log)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn hooks_run_after_their_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = vec![
+            (hook(&log, "echo c >> \"$LOG\""), vec![1, 2]),
+            (hook(&log, "sleep 0.2; echo a >> \"$LOG\""), vec![]),
+            (hook(&log, "echo b >> \"$LOG\""), vec![1]),
+        ];
+        let outputs = run_all(hooks, false, SHELL, false);
+        assert!(outputs.iter().all(HookOutput::success));
+        assert_eq!(read_log(&log), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn skipped_entries_dont_block_dependents() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let hooks = vec![
+            (None, vec![]),
+            (hook(&log, "echo a >> \"$LOG\""), vec![0]),
+            (None, vec![1]),
+            (hook(&log, "echo b >> \"$LOG\""), vec![2]),
+        ];
+        let outputs = run_all(hooks, false, SHELL, false);
+        let labels: Vec<&str> = outputs.iter().map(|output| output.label.as_str()).collect();
+        assert_eq!(labels, ["echo a >> \"$LOG\"", "echo b >> \"$LOG\""]);
+        assert_eq!(read_log(&log), ["a", "b"]);
+    }
+
+    #[test]
+    fn sequential_hooks_run_one_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let command = |name: &str| {
+            format!(
+                "echo start {0} >> \"$LOG\"; sleep 0.1; echo end {0} >> \"$LOG\"",
+                name
+            )
+        };
+        let hooks = vec![
+            (hook(&log, &command("a")), vec![]),
+            (hook(&log, &command("b")), vec![]),
+            (hook(&log, &command("c")), vec![]),
+        ];
+        run_all(hooks, true, SHELL, false);
+        assert_eq!(
+            read_log(&log),
+            ["start a", "end a", "start b", "end b", "start c", "end c"]
+        );
+    }
+}
@@ -1,5 +1,8 @@
+pub mod backup;
 pub mod cli;
 pub mod completions;
 pub mod config;
 pub mod find;
+pub mod history;
+pub mod hook;
 pub mod operations;
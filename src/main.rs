@@ -7,9 +7,12 @@ use dirs::{data_dir, preference_dir};
 use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use flavours::operations::{apply, build, current, generate, info, list, list_templates, update};
+use flavours::operations::{
+    apply, build, check_config, current, generate, history, info, init, list, list_templates, restore,
+    show_config, undo, update, watch,
+};
 use flavours::{cli, completions};
 
 use std::fs::{create_dir_all, write};
@@ -46,19 +49,26 @@ fn main() -> Result<()> {
         .ok_or_else(|| anyhow!("Error getting default config directory"))?
         .join("flavours");
 
+    // init creates the config file, so it doesn't have to exist yet
+    let initializing = matches.subcommand_name() == Some("init");
+    let config_file = |path: &str, source: &str| -> Result<PathBuf> {
+        let path = Path::new(path);
+        if initializing && !path.exists() {
+            return Ok(path.to_path_buf());
+        }
+        path.canonicalize()
+            .with_context(|| format!("Invalid config file supplied on {}", source))
+    };
+
     // Flavours config file
     let flavours_config = match matches.value_of("config") {
         // User supplied
-        Some(path) => Path::new(path)
-            .canonicalize()
-            .with_context(|| "Invalid config file supplied on argument")?,
+        Some(path) => config_file(path, "argument")?,
         // If not supplied
         None => {
             // Try to get from env var
             match env::var("FLAVOURS_CONFIG_FILE") {
-                Ok(path) => Path::new(&path)
-                    .canonicalize()
-                    .with_context(|| "Invalid config file supplied on env var")?,
+                Ok(path) => config_file(&path, "env var")?,
                 // Use default instead
                 Err(_) => flavours_config_dir.join("config.toml"),
             }
@@ -84,19 +94,102 @@ fn main() -> Result<()> {
                 //Defaults to wildcard
                 None => vec!["*"],
             };
-            let light = sub_matches.is_present("light");
-            let from_stdin = sub_matches.is_present("stdin");
+            let source = if sub_matches.is_present("stdin") {
+                apply::SchemeSource::Stdin
+            } else {
+                apply::SchemeSource::Patterns(patterns)
+            };
+            let options = apply::ApplyOptions {
+                light: sub_matches.is_present("light"),
+                dry_run: sub_matches.is_present("dry-run"),
+                verbose,
+                only: match sub_matches.values_of("only") {
+                    Some(values) => values.map(String::from).collect(),
+                    None => Vec::new(),
+                },
+                skip: match sub_matches.values_of("skip") {
+                    Some(values) => values.map(String::from).collect(),
+                    None => Vec::new(),
+                },
+                profile: sub_matches.value_of("profile").map(String::from),
+                force: sub_matches.is_present("force"),
+                skip_backups: false,
+            };
             apply::apply(
-                patterns,
+                source,
+                &flavours_dir,
+                &flavours_config_dir,
+                &flavours_config,
+                &options,
+            )
+        }
+
+        Some(("history", _)) => history::history(&flavours_dir, verbose),
+
+        Some(("undo", sub_matches)) => {
+            let options = apply::ApplyOptions {
+                light: sub_matches.is_present("light"),
+                dry_run: sub_matches.is_present("dry-run"),
+                verbose,
+                ..Default::default()
+            };
+            undo::undo(
                 &flavours_dir,
                 &flavours_config_dir,
                 &flavours_config,
-                light,
-                from_stdin,
+                &options,
+            )
+        }
+
+        Some(("watch", sub_matches)) => {
+            let options = apply::ApplyOptions {
+                light: sub_matches.is_present("light"),
                 verbose,
+                profile: sub_matches.value_of("profile").map(String::from),
+                ..Default::default()
+            };
+            watch::watch(
+                &flavours_dir,
+                &flavours_config_dir,
+                &flavours_config,
+                &options,
             )
         }
 
+        Some(("restore", sub_matches)) => {
+            let at = match sub_matches.value_of("at") {
+                Some(at) => Some(
+                    at.parse()
+                        .with_context(|| format!("Invalid timestamp '{}'", at))?,
+                ),
+                None => None,
+            };
+            let list = sub_matches.is_present("list");
+            let file = sub_matches.value_of("file");
+            if !list && file.is_none() && !sub_matches.is_present("all") {
+                return Err(anyhow!("Specify a file to restore, or use --all"));
+            }
+            restore::restore(file, at, list, &flavours_dir, verbose)
+        }
+
+        Some(("init", sub_matches)) => init::init(
+            &flavours_config,
+            &flavours_dir,
+            &flavours_config_dir,
+            sub_matches.is_present("force"),
+            verbose,
+        ),
+
+        Some(("config", sub_matches)) => match sub_matches.subcommand() {
+            Some(("show", show_matches)) => {
+                show_config::show(&flavours_config, show_matches.is_present("layers"))
+            }
+            Some(("check", _)) => {
+                check_config::check(&flavours_config, &flavours_dir, &flavours_config_dir)
+            }
+            _ => Err(anyhow!("No valid config subcommand specified")),
+        },
+
         Some(("build", sub_matches)) => {
             // Get file paths
             let scheme_file = sub_matches
@@ -105,7 +198,14 @@ fn main() -> Result<()> {
             let template_file = sub_matches
                 .value_of("template")
                 .ok_or_else(|| anyhow!("You must specify a template file"))?;
-            build::build(Path::new(scheme_file), Path::new(template_file))
+            let mut variables = BTreeMap::new();
+            for variable in sub_matches.values_of("var").into_iter().flatten() {
+                let (key, value) = variable
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Variables must be set as KEY=VALUE, got '{}'", variable))?;
+                variables.insert(key.to_string(), value.to_string());
+            }
+            build::build(Path::new(scheme_file), Path::new(template_file), &variables)
         }
 
         Some(("list", sub_matches)) => {
@@ -1,15 +1,29 @@
 use anyhow::{anyhow, Context, Result};
-use base16_color_scheme::Scheme;
+use base16_color_scheme::{
+    scheme::{BaseIndex, RgbColor},
+    Scheme,
+};
+use glob::Pattern;
+use palette::rgb::Rgb;
+use palette::Yxy;
 use rand::seq::SliceRandom;
+use similar::TextDiff;
+use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::io::{self, Read};
 use std::path;
-use std::process;
 use std::str;
-use std::thread;
+use std::time::Duration;
 
-use crate::config::Config;
+use crate::backup;
+use crate::config::{
+    item_dependencies, Condition, Config, ConfigItem, HookMode, InsertMode, TemplateSource,
+    Variant,
+};
 use crate::find::{find_schemes, find_template};
+use crate::history;
+use crate::hook::{self, Hook, HookOutput};
 use crate::operations::build::build_template;
 
 /// Picks a random path, from given vec
@@ -23,136 +37,608 @@ fn random(values: Vec<path::PathBuf>) -> Result<path::PathBuf> {
     Ok(chosen.to_path_buf())
 }
 
-/// Runs hook commands
-///
-/// * `command` - Command string to execute
-/// * `verbose` - Should we be verbose?
-fn run_hook(command: Option<String>, shell: &str, verbose: bool) -> Result<()> {
-    if let Some(command) = command {
-        let full_command = shell.replace("{}", &command);
-        if verbose {
-            println!("running {}", full_command);
-        }
-        let command_vec = shell_words::split(&full_command)?;
-
-        if command_vec.len() == 1 {
-            process::Command::new(&command_vec[0])
-                .stdout(process::Stdio::null())
-                .stderr(process::Stdio::null())
-                .status()
-                .with_context(|| format!("Couldn't run hook '{}'", full_command))?;
-        } else {
-            process::Command::new(&command_vec[0])
-                .args(&command_vec[1..])
-                .stdout(process::Stdio::null())
-                .stderr(process::Stdio::null())
-                .status()
-                .with_context(|| format!("Couldn't run hook '{}'", full_command))?;
-        }
-    }
-
-    Ok(())
-}
-
 /// Replace with delimiter lines
 ///
-/// In a string, removes everything from one line to another, and puts the built template in place
+/// In a string, removes everything between each pair of delimiter lines, and puts the built
+/// template in place
 ///
 /// * `file_content` - String with lines to be replaced
 /// * `start` - Where to start replacing
 /// * `end` - Where to stop replacing
 /// * `built_template` - Built template to be injected
+/// * `indent` - Indent the built template as much as the start line is
+/// * `newline` - Line ending to use
 fn replace_delimiter(
     file_content: &str,
     start: &str,
     end: &str,
     built_template: &str,
+    indent: bool,
+    newline: &str,
 ) -> Result<String> {
     let mut changed_content = String::new();
 
-    let mut found_start = false;
-    let mut found_end = false;
-
-    let mut appended = false;
+    let mut inside: Option<&str> = None;
+    let mut replaced = 0;
 
     for line in file_content.lines() {
-        if found_start && !found_end {
-            if !appended {
-                changed_content.push_str(&format!("{}\n", built_template));
-                appended = true;
-            }
+        if let Some(indentation) = inside {
             if line.trim().to_lowercase().eq(&end) {
-                changed_content.push_str(&format!("{}\n", line));
-                found_end = true;
+                for template_line in built_template.split('\n') {
+                    let template_line = template_line.strip_suffix('\r').unwrap_or(template_line);
+                    if !template_line.is_empty() {
+                        changed_content.push_str(indentation);
+                    }
+                    changed_content.push_str(template_line);
+                    changed_content.push_str(newline);
+                }
+                changed_content.push_str(line);
+                changed_content.push_str(newline);
+                inside = None;
+                replaced += 1;
             }
         } else {
-            changed_content.push_str(&format!("{}\n", line));
+            changed_content.push_str(line);
+            changed_content.push_str(newline);
             if line.trim().to_lowercase().eq(&start) {
-                found_start = true
+                let indentation = &line[..line.len() - line.trim_start().len()];
+                inside = Some(if indent { indentation } else { "" });
             }
         }
     }
-    if !found_start {
-        Err(anyhow!("Couldn't find starting string."))
-    } else if !found_end {
+    if inside.is_some() {
         Err(anyhow!("Couldn't find ending string."))
+    } else if replaced == 0 {
+        Err(anyhow!("Couldn't find starting string."))
     } else {
         Ok(changed_content)
     }
 }
 
+/// Line ending used by a file, going by its first line
+///
+/// * `file_content` - File content
+fn line_ending(file_content: &str) -> &'static str {
+    match file_content.find('\n') {
+        Some(index) if file_content[..index].ends_with('\r') => "\r\n",
+        _ => "\n",
+    }
+}
+
+/// Add an empty delimited block for an item to a file that doesn't have one
+///
+/// * `file_content` - File content
+/// * `item` - Item whose delimiters are added
+/// * `insert` - Where to add them, if at all
+/// * `newline` - Line ending to use
+fn insert_block(
+    file_content: &str,
+    item: &ConfigItem,
+    insert: InsertMode,
+    newline: &str,
+) -> String {
+    let start = item.start_delimiter();
+    if file_content
+        .lines()
+        .any(|line| line.trim().to_lowercase().eq(&start))
+    {
+        return file_content.to_string();
+    }
+
+    let block = format!("{}{}{}{}", item.start_line(), newline, item.end_line(), newline);
+    match insert {
+        InsertMode::Error => file_content.to_string(),
+        InsertMode::Prepend => format!("{}{}", block, file_content),
+        InsertMode::Append if file_content.is_empty() || file_content.ends_with('\n') => {
+            format!("{}{}", file_content, block)
+        }
+        InsertMode::Append => format!("{}{}{}", file_content, newline, block),
+    }
+}
+
+/// Read a scheme file, returning its content and slug
+///
+/// * `scheme_file` - Path to the scheme
+fn read_scheme(scheme_file: &path::Path) -> Result<(String, String)> {
+    let scheme_slug = scheme_file
+        .file_stem()
+        .ok_or_else(|| anyhow!("Couldn't get scheme name."))?
+        .to_str()
+        .ok_or_else(|| anyhow!("Couldn't convert scheme file name."))?
+        .into();
+
+    //Read chosen scheme
+    let scheme_contents = fs::read_to_string(scheme_file)
+        .with_context(|| format!("Couldn't read scheme file at {:?}.", scheme_file))?;
+
+    Ok((scheme_contents, scheme_slug))
+}
+
+/// Pick the scheme for an item with its own `scheme` pattern
+///
+/// `{slug}` on the pattern is replaced with the applied scheme slug (e.g. `{slug}-light`), and
+/// such patterns fall back to the applied scheme (returning none) if nothing matches.
+///
+/// * `pattern` - Item scheme pattern
+/// * `applied` - Scheme being applied
+/// * `base_dir` - Flavours base directory
+/// * `config_dir` - Flavours config directory
+fn item_scheme(
+    pattern: &str,
+    applied: &Scheme,
+    base_dir: &path::Path,
+    config_dir: &path::Path,
+) -> Result<Option<Scheme>> {
+    let relative = pattern.contains("{slug}");
+    let pattern = pattern.replace("{slug}", &applied.slug);
+    let mut schemes = find_schemes(&pattern, base_dir, config_dir)?;
+    if schemes.is_empty() && relative {
+        return Ok(None);
+    }
+    schemes.sort();
+    schemes.dedup();
+
+    let scheme_file = random(schemes)
+        .with_context(|| format!("No scheme matches item scheme pattern '{}'", pattern))?;
+    let (contents, slug) = read_scheme(&scheme_file)?;
+    let mut scheme: Scheme = serde_yaml::from_str(&contents)
+        .with_context(|| format!("Couldn't parse scheme file {:?}", scheme_file))?;
+    scheme.slug = slug;
+    Ok(Some(scheme))
+}
+
+/// Whether a scheme is dark or light, going by its background (base00) luminance
+///
+/// * `scheme` - Scheme to check
+fn variant(scheme: &Scheme) -> Variant {
+    let [r, g, b] = match scheme.colors.get(&BaseIndex(0)) {
+        Some(RgbColor(color)) => *color,
+        None => return Variant::Dark,
+    };
+    let color: Rgb = Rgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let (_, _, luma) = Yxy::from(color).into_components();
+    // Relative luminance of a perceptual middle gray
+    if luma < 0.18 {
+        Variant::Dark
+    } else {
+        Variant::Light
+    }
+}
+
+/// Describe the first condition about this machine (hostname, environment, files) an item
+/// doesn't meet, if any
+///
+/// These don't depend on the scheme, so they're checked before picking one.
+///
+/// * `when` - Item conditions
+fn unmet_condition(when: &Condition) -> Result<Option<String>> {
+    if let Some(hostname) = &when.hostname {
+        let pattern = Pattern::new(hostname)
+            .with_context(|| format!("Invalid hostname pattern '{}'", hostname))?;
+        let actual = gethostname::gethostname().to_string_lossy().into_owned();
+        if !pattern.matches(&actual) {
+            return Ok(Some(format!("hostname '{}' doesn't match '{}'", actual, hostname)));
+        }
+    }
+    for (name, value) in when.env.iter().flatten() {
+        let pattern = Pattern::new(value)
+            .with_context(|| format!("Invalid pattern '{}' for environment variable {}", value, name))?;
+        match env::var(name) {
+            Ok(actual) if pattern.matches(&actual) => {}
+            Ok(actual) => {
+                return Ok(Some(format!("${} is '{}', which doesn't match '{}'", name, actual, value)))
+            }
+            Err(_) => return Ok(Some(format!("${} is not set", name))),
+        }
+    }
+    if let Some(exists) = &when.exists {
+        match shellexpand::full(exists) {
+            Ok(file) if path::Path::new(file.as_ref()).exists() => {}
+            Ok(file) => return Ok(Some(format!("{:?} doesn't exist", file))),
+            Err(error) => return Ok(Some(format!("couldn't expand '{}' ({})", exists, error))),
+        }
+    }
+    Ok(None)
+}
+
+/// Describe why an item's scheme variant condition isn't met, if it isn't
+///
+/// * `when` - Item conditions
+/// * `scheme` - Scheme the item would be built with
+fn unmet_variant(when: &Condition, scheme: &Scheme) -> Option<String> {
+    let expected = when.variant?;
+    let actual = variant(scheme);
+    if actual != expected {
+        return Some(format!("scheme is {}, not {}", actual, expected));
+    }
+    None
+}
+
+/// A file that is about to be written by apply
+struct PendingWrite {
+    /// Where to write, with symlinks already resolved
+    path: path::PathBuf,
+    /// File content before applying, if the file existed
+    original: Option<Vec<u8>>,
+    /// New content, if any item changed it
+    content: Option<String>,
+    /// Mode to set on the file after writing it, if any item asked for one
+    mode: Option<u32>,
+}
+
+impl PendingWrite {
+    /// Read the original content of the file, so it can be restored later
+    fn new(path: path::PathBuf) -> Result<PendingWrite> {
+        let original = match fs::read(&path) {
+            Ok(content) => Some(content),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => {
+                return Err(error).with_context(|| format!("Couldn't read file {:?}", path))
+            }
+        };
+        Ok(PendingWrite {
+            path,
+            original,
+            content: None,
+            mode: None,
+        })
+    }
+
+    /// Whether there is any content yet, either from the file or from previous items
+    fn exists(&self) -> bool {
+        self.original.is_some() || self.content.is_some()
+    }
+
+    /// Content as it'll be written, taking previous items into account
+    fn current_content(&self) -> Result<String> {
+        match (&self.content, &self.original) {
+            (Some(content), _) => Ok(content.clone()),
+            (None, Some(original)) => Ok(str::from_utf8(original)?.to_string()),
+            (None, None) => Err(anyhow!("File does not exist")),
+        }
+    }
+
+    /// Whether applying changes the file (new content differs from the original)
+    fn changed(&self) -> bool {
+        match (&self.original, &self.content) {
+            (Some(original), Some(content)) => original != content.as_bytes(),
+            (None, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+
+    /// Save a backup of the original content, if the file existed and is going to change
+    ///
+    /// * `base_dir` - Flavours base directory
+    /// * `timestamp` - Backup timestamp
+    /// * `keep` - How many backups to keep for this file
+    fn backup(&self, base_dir: &path::Path, timestamp: u64, keep: usize) -> Result<()> {
+        match &self.original {
+            Some(original) if self.changed() => {
+                backup::save(base_dir, &self.path, original, timestamp, keep)
+                    .with_context(|| format!("Couldn't back up {:?}", self.path))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Put the original content back in place (or remove the file, if it didn't exist)
+    fn rollback(&self) -> Result<()> {
+        match &self.original {
+            Some(original) => write_file(&self.path, original),
+            None => Ok(fs::remove_file(&self.path)?),
+        }
+    }
+}
+
+/// Resolve symlinks on a file path, so we write to the link target instead of replacing the link
+///
+/// * `file` - File path, which may not exist yet
+pub fn resolve_path(file: &str) -> path::PathBuf {
+    fs::canonicalize(file).unwrap_or_else(|_| path::PathBuf::from(file))
+}
+
+/// Give a file the same owner and group as an existing one, if they differ
+///
+/// * `file` - File to change
+/// * `metadata` - Metadata of the file to take ownership from
+#[cfg(unix)]
+fn copy_ownership(file: &path::Path, metadata: &fs::Metadata) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let current = fs::metadata(file)?;
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+    let file = CString::new(file.as_os_str().as_bytes())?;
+    // SAFETY: the path is a valid, nul terminated string that outlives the call
+    if unsafe { libc::chown(file.as_ptr(), metadata.uid(), metadata.gid()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_file: &path::Path, _metadata: &fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+/// Set a file's mode (permission bits), if it's not already set
+///
+/// * `file` - File to change
+/// * `mode` - Mode to set (e.g. 0o755)
+#[cfg(unix)]
+fn set_mode(file: &path::Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(file)?.permissions();
+    if permissions.mode() & 0o7777 == mode {
+        return Ok(());
+    }
+    permissions.set_mode(mode);
+    fs::set_permissions(file, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_mode(_file: &path::Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Atomically write a file
+///
+/// Writes to a temporary file on the same directory, and then renames it over the target.
+/// Permissions of the existing file are kept, and so is its ownership if we're allowed to (only
+/// root can give files away).
+///
+/// * `file` - Path to write to
+/// * `content` - Content to be written
+pub fn write_file(file: &path::Path, content: &[u8]) -> Result<()> {
+    let parent = file
+        .parent()
+        .ok_or_else(|| anyhow!("Couldn't get parent directory of {:?}", file))?;
+    let file_name = file
+        .file_name()
+        .ok_or_else(|| anyhow!("Couldn't get file name of {:?}", file))?
+        .to_string_lossy();
+    fs::create_dir_all(parent)?;
+
+    let temp_file = parent.join(format!(".{}.flavours", file_name));
+    fs::write(&temp_file, content)?;
+    let result = match fs::metadata(file) {
+        Ok(metadata) => fs::set_permissions(&temp_file, metadata.permissions()).and_then(|_| {
+            match copy_ownership(&temp_file, &metadata) {
+                Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                    eprintln!("Couldn't keep the owner and group of {:?}: {}", file, error);
+                    Ok(())
+                }
+                result => result,
+            }
+        }),
+        Err(_) => Ok(()),
+    }
+    .and_then(|_| fs::rename(&temp_file, file));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    Ok(result?)
+}
+
+/// Record an applied scheme on the history
+///
+/// * `base_dir` - Flavours base directory
+/// * `slug` - Applied scheme slug
+/// * `scheme_contents` - Applied scheme content, kept if it didn't come from a file
+/// * `scheme_file` - Applied scheme file, if any
+/// * `timestamp` - When the scheme was applied
+fn record(
+    base_dir: &path::Path,
+    slug: &str,
+    scheme_contents: &str,
+    scheme_file: Option<path::PathBuf>,
+    timestamp: u64,
+) -> Result<()> {
+    let (scheme_file, source) = match scheme_file {
+        Some(scheme_file) => (scheme_file, history::Source::Pattern),
+        None => {
+            let scheme_file = history::stdin_scheme_file(base_dir, timestamp, slug);
+            write_file(&scheme_file, scheme_contents.as_bytes())
+                .with_context(|| format!("Couldn't store scheme at {:?}", scheme_file))?;
+            (scheme_file, history::Source::Stdin)
+        }
+    };
+    history::append(
+        base_dir,
+        history::Entry {
+            timestamp,
+            slug: slug.into(),
+            scheme_file,
+            source,
+        },
+    )
+}
+
+/// Global (not item specific) hook, if configured and not skipped due to lightweight mode
+///
+/// * `label` - Hook name, used when reporting
+/// * `command` - Configured command
+/// * `light` - Whether the hook is lightweight (defaults to true)
+/// * `light_mode` - Don't run hooks marked as non-lightweight
+/// * `timeout` - Kill the hook if it runs for longer than this
+/// * `env` - Environment variables describing the scheme
+fn global_hook(
+    label: &str,
+    command: &Option<String>,
+    light: Option<bool>,
+    light_mode: bool,
+    timeout: Option<Duration>,
+    env: &[(String, String)],
+) -> Option<Hook> {
+    match command {
+        Some(command) if !light_mode || light.unwrap_or(true) => Some(Hook {
+            label: label.into(),
+            command: command.clone(),
+            timeout,
+            env: env.to_vec(),
+        }),
+        _ => None,
+    }
+}
+
+/// Report how hooks went
+///
+/// Writes their output to a log file (replaced on every apply), shows it when verbose, and lists
+/// failed hooks.
+///
+/// * `outputs` - Hook outcomes
+/// * `base_dir` - Flavours base directory
+/// * `verbose` - Should we be verbose?
+fn report_hooks(outputs: &[HookOutput], base_dir: &path::Path, verbose: bool) -> Result<()> {
+    let log_file = base_dir.join("hooks.log");
+    let log: String = outputs.iter().map(HookOutput::report).collect();
+    fs::write(&log_file, &log).with_context(|| format!("Couldn't write hook log {:?}", log_file))?;
+
+    if verbose {
+        print!("{}", log);
+    }
+
+    let failed: Vec<&HookOutput> = outputs.iter().filter(|output| !output.success()).collect();
+    if !failed.is_empty() {
+        eprintln!("Some hooks failed (output logged to {:?}):", log_file);
+        for output in failed {
+            eprintln!("  {}: {}", output.label, output.status());
+        }
+    }
+    Ok(())
+}
+
+/// Print a unified diff between the current and the new content of a file
+///
+/// * `file` - Path of the file being changed, used on the diff header
+/// * `old_content` - Current file content
+/// * `new_content` - Content that would be written
+fn print_diff(file: &str, old_content: &str, new_content: &str) {
+    let diff = TextDiff::from_lines(old_content, new_content);
+    print!("{}", diff.unified_diff().header(file, file));
+}
+
+/// Where to get the scheme to be applied from
+pub enum SchemeSource<'a> {
+    /// Pick one randomly, between schemes matching any of the patterns
+    Patterns(Vec<&'a str>),
+    /// Read scheme from stdin
+    Stdin,
+    /// Use this scheme file, without recording it on the history (e.g. when undoing)
+    File(path::PathBuf),
+}
+
+/// Parse glob patterns given on the command line
+///
+/// * `patterns` - Pattern strings
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("Invalid pattern '{}'", pattern))
+        })
+        .collect()
+}
+
+/// Options for the apply function
+#[derive(Clone, Default)]
+pub struct ApplyOptions {
+    /// Don't run hooks marked as non-lightweight
+    pub light: bool,
+    /// Only print what would change, without writing files or running hooks
+    pub dry_run: bool,
+    /// Should we be verbose?
+    pub verbose: bool,
+    /// Only apply items matching any of these patterns (by id, template or file)
+    pub only: Vec<String>,
+    /// Don't apply items matching any of these patterns (by id, template or file)
+    pub skip: Vec<String>,
+    /// Apply items from this profile, instead of the default one
+    pub profile: Option<String>,
+    /// Write files and run their hooks even if their content didn't change
+    pub force: bool,
+    /// Don't take backups of changed files (e.g. when applying over and over while watching)
+    pub skip_backups: bool,
+}
+
+/// Does the item match any of the glob patterns, by id, template or file?
+///
+/// * `item` - Configured item
+/// * `file` - Item file, after expansion
+/// * `patterns` - Patterns to match against
+fn item_matches(item: &ConfigItem, file: &str, patterns: &[Pattern]) -> bool {
+    let candidates = [
+        item.id.as_deref(),
+        item.template.as_deref(),
+        Some(item.file.as_str()),
+        Some(file),
+    ];
+    patterns.iter().any(|pattern| {
+        candidates
+            .iter()
+            .flatten()
+            .any(|candidate| pattern.matches(candidate))
+    })
+}
+
 /// Apply function
 ///
-/// * `patterns` - Which patterns the user specified
+/// * `source` - Where to get the scheme from
 /// * `base_dir` - Flavours base directory
 /// * `config_path` - Flavours configuration path
-/// * `light` - Don't run hooks marked as non-lightweight
-/// * `from_stdin` - Read scheme from stdin?
-/// * `verbose` - Should we be verbose?
+/// * `options` - Apply options (light mode, dry run, verbosity)
 pub fn apply(
-    patterns: Vec<&str>,
+    source: SchemeSource,
     base_dir: &path::Path,
     config_dir: &path::Path,
     config_path: &path::Path,
-    light_mode: bool,
-    from_stdin: bool,
-    verbose: bool,
+    options: &ApplyOptions,
 ) -> Result<()> {
-    let (scheme_contents, scheme_slug) = if from_stdin {
-        let mut buffer = String::new();
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-        handle.read_to_string(&mut buffer)?;
-        (buffer, String::from("generated"))
-    } else {
-        //Find schemes that match given patterns
-        let mut schemes = Vec::new();
-        for pattern in patterns {
-            let found_schemes = find_schemes(pattern, base_dir, config_dir)?;
+    let light_mode = options.light;
+    let dry_run = options.dry_run;
+    let verbose = options.verbose;
+    let force = options.force;
 
-            for found_scheme in found_schemes {
-                schemes.push(found_scheme);
+    // Schemes applied from a given file are not recorded
+    let record_history = !matches!(source, SchemeSource::File(_));
+
+    let (scheme_contents, scheme_slug, scheme_file) = match source {
+        SchemeSource::Stdin => {
+            let mut buffer = String::new();
+            let stdin = io::stdin();
+            let mut handle = stdin.lock();
+            handle.read_to_string(&mut buffer)?;
+            (buffer, String::from("generated"), None)
+        }
+        SchemeSource::Patterns(patterns) => {
+            //Find schemes that match given patterns
+            let mut schemes = Vec::new();
+            for pattern in patterns {
+                let found_schemes = find_schemes(pattern, base_dir, config_dir)?;
+
+                for found_scheme in found_schemes {
+                    schemes.push(found_scheme);
+                }
             }
+            //Sort and remove duplicates
+            schemes.sort();
+            schemes.dedup();
+
+            //Get random scheme
+            let scheme_file = random(schemes)?;
+            let (contents, slug) = read_scheme(&scheme_file)?;
+            (contents, slug, Some(scheme_file))
+        }
+        SchemeSource::File(scheme_file) => {
+            let (contents, slug) = read_scheme(&scheme_file)?;
+            (contents, slug, None)
         }
-        //Sort and remove duplicates
-        schemes.sort();
-        schemes.dedup();
-
-        //Get random scheme
-        let scheme_file = random(schemes)?;
-        let scheme_slug = scheme_file
-            .file_stem()
-            .ok_or_else(|| anyhow!("Couldn't get scheme name."))?
-            .to_str()
-            .ok_or_else(|| anyhow!("Couldn't convert scheme file name."))?
-            .into();
-
-        //Read chosen scheme
-        (
-            fs::read_to_string(&scheme_file)
-                .with_context(|| format!("Couldn't read scheme file at {:?}.", scheme_file))?,
-            scheme_slug,
-        )
     };
 
     let mut scheme: Scheme = serde_yaml::from_str(&scheme_contents)?;
@@ -166,34 +652,10 @@ pub fn apply(
         println!();
     }
 
-    //Check if config file exists
-    if !config_path.exists() {
-        eprintln!("Config {:?} doesn't exist, creating", config_path);
-        let default_content = match fs::read_to_string(path::Path::new("/etc/flavours.conf")) {
-            Ok(content) => content,
-            Err(_) => String::from(""),
-        };
-        let config_path_parent = config_path
-            .parent()
-            .with_context(|| format!("Couldn't get parent directory of {:?}", config_path))?;
-
-        fs::create_dir_all(config_path_parent).with_context(|| {
-            format!(
-                "Couldn't create configuration file parent directory {:?}",
-                config_path_parent
-            )
-        })?;
-        fs::write(config_path, default_content)
-            .with_context(|| format!("Couldn't create configuration file at {:?}", config_path))?;
-    }
-
-    let config_contents = fs::read_to_string(config_path)
-        .with_context(|| format!("Couldn't read configuration file {:?}.", config_path))?;
-
-    let config = Config::read(&config_contents, config_path)?;
+    let config = Config::load(config_path)?;
 
     // If shell is present, check if it contains the placeholder
-    let shell = config.shell.unwrap_or_else(|| "sh -c '{}'".into());
+    let shell = config.shell.clone().unwrap_or_else(|| "sh -c '{}'".into());
 
     if !shell.contains("{}") {
         // Hide {} in this error message from the formatting machinery in anyhow macro
@@ -201,32 +663,78 @@ pub fn apply(
         return Err(anyhow!(msg));
     }
 
-    let mut hooks = Vec::new();
-
     //Iterate configurated entries (templates)
-    let items_legacy = config.item.unwrap_or_default();
-    let mut items = config.items.unwrap_or_default();
-    items.extend(items_legacy.into_iter());
+    let items = config.items(options.profile.as_deref())?;
 
     if items.is_empty() {
-        return Err(anyhow!("Couldn't get items from config file. Check the default file or github for config examples."));
+        return Err(anyhow!("Couldn't get items from config file. Run 'flavours init' to create one, or check the default file or github for config examples."));
     }
 
+    let dependencies = item_dependencies(&items)?;
+
+    // Render every item before touching any file, so a failing item can't leave things half-applied
+    let mut writes: Vec<PendingWrite> = Vec::new();
+    let mut hook_queue = Vec::new();
+    // Schemes picked by item scheme patterns, so items sharing a pattern get the same one
+    let mut item_schemes: HashMap<String, Option<Scheme>> = HashMap::new();
+    let hook_timeout = config.hook_timeout.map(Duration::from_secs);
+
+    let only = compile_patterns(&options.only)?;
+    let skip = compile_patterns(&options.skip)?;
+    let mut selected = 0;
+
     for item in items.iter() {
-        //Template name
-        let template = &item.template;
-        //Subtemplate name
-        let mut subtemplate = match &item.subtemplate {
-            Some(value) => String::from(value),
-            None => String::from("default"),
+        //File to write
+        let file = shellexpand::full(&item.file)?.to_string();
+
+        // Items left out stay on the hook queue as no-ops, so items coming after them still run
+        let included = only.is_empty() || item_matches(item, &file, &only);
+        if !included || item_matches(item, &file, &skip) {
+            if verbose {
+                println!("Skipping {:?}", item.file);
+            }
+            hook_queue.push(None);
+            continue;
+        }
+        selected += 1;
+
+        // Items that don't apply here are left out, like the ones filtered above. Checked before
+        // picking the item scheme, which may not even exist on other machines
+        let unmet = match &item.when {
+            Some(when) => unmet_condition(when)?,
+            None => None,
         };
-        if subtemplate == "{scheme}" {
-            let subtemplate_scheme = find_template(template, &scheme.scheme, base_dir, config_dir);
-            subtemplate = match subtemplate_scheme {
-                Ok(_value) => scheme.scheme.clone(),
-                Err(_e) => String::from("default"),
+        if let Some(reason) = unmet {
+            if verbose {
+                println!("Skipping {:?}: {}", item.file, reason);
             }
+            hook_queue.push(None);
+            continue;
+        }
+
+        //Scheme, unless the item picks its own
+        let scheme = match &item.scheme {
+            Some(pattern) => {
+                if !item_schemes.contains_key(pattern) {
+                    let picked = item_scheme(pattern, &scheme, base_dir, config_dir)
+                        .with_context(|| format!("Couldn't pick a scheme for {:?}", item.file))?;
+                    item_schemes.insert(pattern.clone(), picked);
+                }
+                item_schemes[pattern].as_ref().unwrap_or(&scheme)
+            }
+            None => &scheme,
         };
+        if let Some(reason) = item.when.as_ref().and_then(|when| unmet_variant(when, scheme)) {
+            if verbose {
+                println!("Skipping {:?}: {}", item.file, reason);
+            }
+            hook_queue.push(None);
+            continue;
+        }
+        if verbose {
+            println!("Scheme for {:?}: {} ({})", item.file, scheme.scheme, scheme.slug);
+        }
+
         //Is the hook lightweight?
         let light = match &item.light {
             Some(value) => *value,
@@ -238,83 +746,267 @@ pub fn apply(
             None => false,
         };
         //Replace start delimiter
-        let start = match &item.start {
-            Some(value) => String::from(value),
-            None => String::from("# Start flavours"),
-        }
-        .trim()
-        .to_lowercase();
+        let start = item.start_delimiter();
         //Replace end delimiter
-        let end = match &item.end {
-            Some(value) => String::from(value),
-            None => String::from("# End flavours"),
-        }
-        .trim()
-        .to_lowercase();
+        let end = item.end_delimiter();
 
-        let subtemplate_file = find_template(template, &subtemplate, base_dir, config_dir)
-            .with_context(|| {
-                format!(
-                    "Failed to locate subtemplate file {}/{}",
-                    template, subtemplate,
-                )
-            })?;
+        //Template content, along with template and subtemplate names (none for inline templates)
+        let (template_content, names) = match item.template_source()? {
+            TemplateSource::Named {
+                template,
+                subtemplate,
+            } => {
+                let mut subtemplate = String::from(subtemplate);
+                if subtemplate == "{scheme}" {
+                    let subtemplate_scheme =
+                        find_template(template, &scheme.scheme, base_dir, config_dir);
+                    subtemplate = match subtemplate_scheme {
+                        Ok(_value) => scheme.scheme.clone(),
+                        Err(_e) => String::from("default"),
+                    }
+                };
 
-        //Template content
-        let template_content = fs::read_to_string(&subtemplate_file)
-                       .with_context(||format!("Couldn't read template {}/{} at {:?}. Check if the correct template/subtemplate was specified, and run the update templates command if you didn't already.", template, subtemplate, subtemplate_file))?;
+                let subtemplate_file = find_template(template, &subtemplate, base_dir, config_dir)
+                    .with_context(|| {
+                        format!(
+                            "Failed to locate subtemplate file {}/{}",
+                            template, subtemplate,
+                        )
+                    })?;
+
+                let template_content = fs::read_to_string(&subtemplate_file)
+                    .with_context(||format!("Couldn't read template {}/{} at {:?}. Check if the correct template/subtemplate was specified, and run the update templates command if you didn't already.", template, subtemplate, subtemplate_file))?;
+                (template_content, Some((String::from(template), subtemplate)))
+            }
+            TemplateSource::Inline(inline) => (String::from(inline), None),
+        };
 
         //Template with correct colors
-        let built_template = build_template(&template_content, &scheme)
+        let built_template = build_template(&template_content, scheme, &config.variables(item))
             .context("Couldn't replace placeholders. Check if all colors on the specified scheme file are valid (don't include a leading '#').")?;
 
-        //File to write
-        let file = shellexpand::full(&item.file)?.to_string();
+        let path = resolve_path(&file);
 
-        //Rewrite file with built template
-        if rewrite {
-            std::path::Path::new(&file)
-                .parent()
-                .and_then(|p| fs::create_dir_all(p).ok());
-            fs::write(&file, built_template)
-                .with_context(|| format!("Couldn't write to file {:?}.", file))?;
+        // Items targeting a file already rendered by a previous item build upon its content
+        let index = match writes.iter().position(|write| write.path == path) {
+            Some(index) => index,
+            None => {
+                writes.push(PendingWrite::new(path)?);
+                writes.len() - 1
+            }
+        };
+        let write = &mut writes[index];
 
-            if verbose {
-                println!("Wrote {}/{} on: {:?}", template, subtemplate, file)
+        if let Some(mode) = item.mode()? {
+            match write.mode {
+                Some(other) if other != mode => {
+                    return Err(anyhow!(
+                        "Items targeting {:?} set different modes ({:o} and {:o})",
+                        write.path,
+                        other,
+                        mode
+                    ))
+                }
+                _ => write.mode = Some(mode),
             }
+        }
+
+        //Rewrite file with built template
+        if rewrite {
+            write.content = Some(built_template);
         } else {
             //Or replace with delimiters
-            let file_content = fs::read_to_string(&file)?;
-            match replace_delimiter(&file_content, &start, &end, &built_template) {
-                Ok(content) => fs::write(&file, content)
-                    .with_context(|| format!("Couldn't write to file {:?}", file))?,
-                Err(error) => eprintln!("Couldn't replace lines in {:?}: {}", file, error),
+            let insert = item.insert.unwrap_or(InsertMode::Error);
+            let file_content = if write.exists() || insert == InsertMode::Error {
+                write
+                    .current_content()
+                    .with_context(|| format!("Couldn't read file {:?}", file))?
+            } else {
+                String::new()
+            };
+            // Files are written with LF line endings, unless asked to keep the original ones
+            let newline = match item.keep_line_endings {
+                Some(true) => line_ending(&file_content),
+                _ => "\n",
+            };
+            let indent = item.indent.unwrap_or(true);
+            let file_content = insert_block(&file_content, item, insert, newline);
+            // Nothing was written yet, so failing here leaves every file untouched
+            let content =
+                replace_delimiter(&file_content, &start, &end, &built_template, indent, newline)
+                    .with_context(|| format!("Couldn't replace lines in {:?}", file))?;
+            write.content = Some(content);
+        }
+
+        if verbose && !dry_run {
+            match &names {
+                Some((template, subtemplate)) => {
+                    println!("Built {}/{} for {:?}", template, subtemplate, file)
+                }
+                None => println!("Built inline template for {:?}", file),
             }
-            if verbose {
-                println!("Wrote {}/{} on {:?}", template, subtemplate, file);
+        }
+
+        match &item.hook {
+            // Only add hook to queue if either:
+            // - Not running on lightweight mode
+            // - Hook is set as lightweight
+            Some(command) if !light_mode || light => {
+                let mut env = hook::scheme_env(scheme);
+                env.push(("FLAVOURS_ITEM_FILE".into(), file.clone()));
+                if let Some((template, subtemplate)) = &names {
+                    env.push(("FLAVOURS_ITEM_TEMPLATE".into(), template.clone()));
+                    env.push(("FLAVOURS_ITEM_SUBTEMPLATE".into(), subtemplate.clone()));
+                }
+                let hook = Hook {
+                    label: item.id.clone().unwrap_or_else(|| item.file.clone()),
+                    command: command.clone(),
+                    timeout: item.timeout.map(Duration::from_secs).or(hook_timeout),
+                    env,
+                };
+                hook_queue.push(Some((hook, index)));
             }
+            _ => hook_queue.push(None),
+        }
+    }
+
+    if selected == 0 {
+        return Err(anyhow!("No items left to apply after --only/--skip filtering"));
+    }
+
+    if dry_run {
+        for write in writes.iter().filter(|write| write.changed()) {
+            if let Some(content) = &write.content {
+                let original = write.original.as_deref().unwrap_or_default();
+                print_diff(
+                    &write.path.to_string_lossy(),
+                    &String::from_utf8_lossy(original),
+                    content,
+                );
+            }
+        }
+        return Ok(());
+    }
+
+    // Same timestamp is used for backups and history, so they can be matched when restoring
+    let timestamp = history::next_timestamp(base_dir)?;
+    let keep_backups = if options.skip_backups {
+        0
+    } else {
+        config.backups.unwrap_or(backup::DEFAULT_BACKUPS)
+    };
+
+    let fail_on_hook_error = config.fail_on_hook_error.unwrap_or(false);
+    let mut outputs = Vec::new();
+    let scheme_env = hook::scheme_env(&scheme);
+
+    // Global hook before writing anything (e.g. pause a compositor)
+    let pre_hook = global_hook(
+        "pre_hook",
+        &config.pre_hook,
+        config.pre_hook_light,
+        light_mode,
+        hook_timeout,
+        &scheme_env,
+    );
+    if let Some(hook) = pre_hook {
+        let output = hook.run(&shell, verbose);
+        let failed = !output.success();
+        outputs.push(output);
+        if failed && fail_on_hook_error {
+            report_hooks(&outputs, base_dir, verbose)?;
+            return Err(anyhow!("Pre-apply hook failed, nothing was applied"));
         }
+    }
 
-        let command = item.hook.clone();
-        let shell = shell.clone();
-        // Only add hook to queue if either:
-        // - Not running on lightweight mode
-        // - Hook is set as lightweight
-        if !light_mode || light {
-            hooks.push(thread::spawn(move || run_hook(command, &shell, verbose)));
+    // Write all files, rolling back the ones already written if anything fails
+    let mut written: Vec<&PendingWrite> = Vec::new();
+    let mut result = Ok(());
+    for write in &writes {
+        if let Some(content) = &write.content {
+            if write.changed() || force {
+                result = write.backup(base_dir, timestamp, keep_backups).and_then(|_| {
+                    write_file(&write.path, content.as_bytes())
+                        .with_context(|| format!("Couldn't write to file {:?}", write.path))
+                });
+                if result.is_err() {
+                    break;
+                }
+                written.push(write);
+                if verbose {
+                    println!("Wrote {:?}", write.path);
+                }
+            } else if verbose {
+                println!("Unchanged {:?}", write.path);
+            }
+        }
+        // Unchanged files get their mode set as well, as it may have been changed since
+        if let Some(mode) = write.mode {
+            result = set_mode(&write.path, mode)
+                .with_context(|| format!("Couldn't set mode {:o} on {:?}", mode, write.path));
+            if result.is_err() {
+                break;
+            }
         }
     }
+    if result.is_ok() {
+        let last_scheme_file = &base_dir.join("lastscheme");
+        result = fs::write(last_scheme_file, scheme.scheme_slug())
+            .with_context(|| "Couldn't update applied scheme name");
+    }
+    if result.is_ok() && record_history {
+        result = record(base_dir, &scheme.slug, &scheme_contents, scheme_file, timestamp)
+            .context("Couldn't update history");
+    }
+    if let Err(error) = result {
+        for write in written.into_iter().rev() {
+            if let Err(rollback_error) = write.rollback() {
+                eprintln!("Couldn't restore {:?}: {:#}", write.path, rollback_error);
+            }
+        }
+        return Err(error.context("Nothing was applied, all changed files were restored"));
+    }
+
+    // Hooks of items whose file didn't change have nothing to reload
+    let hook_queue: Vec<Option<Hook>> = hook_queue
+        .into_iter()
+        .map(|entry| match entry {
+            Some((hook, index)) if !force && !writes[index].changed() => {
+                if verbose {
+                    println!("Skipping hook for {}, file is unchanged", hook.label);
+                }
+                None
+            }
+            entry => entry.map(|(hook, _)| hook),
+        })
+        .collect();
+
+    let sequential = config.hooks == Some(HookMode::Sequential);
+    outputs.extend(hook::run_all(
+        hook_queue.into_iter().zip(dependencies).collect(),
+        sequential,
+        &shell,
+        verbose,
+    ));
 
-    let last_scheme_file = &base_dir.join("lastscheme");
-    fs::write(&last_scheme_file, &scheme.scheme_slug())
-        .with_context(|| "Couldn't update applied scheme name")?;
+    // Global hook after every item hook is done (e.g. send a notification)
+    let post_hook = global_hook(
+        "post_hook",
+        &config.post_hook,
+        config.post_hook_light,
+        light_mode,
+        hook_timeout,
+        &scheme_env,
+    );
+    if let Some(hook) = post_hook {
+        outputs.push(hook.run(&shell, verbose));
+    }
+
+    report_hooks(&outputs, base_dir, verbose)?;
 
-    while !hooks.is_empty() {
-        hooks
-            .pop()
-            .ok_or_else(|| anyhow!("Couldn't pop hooks."))?
-            .join()
-            .unwrap()?;
+    let failed = outputs.iter().filter(|output| !output.success()).count();
+    if failed > 0 && fail_on_hook_error {
+        return Err(anyhow!("{} hook(s) failed", failed));
     }
 
     if verbose {
@@ -322,3 +1014,138 @@ pub fn apply(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_every_block() {
+        let content =
+            "a\n# Start flavours\nold\n# End flavours\nb\n# start flavours\n# end flavours\n";
+        let replaced = replace_delimiter(
+            content,
+            "# start flavours",
+            "# end flavours",
+            "new",
+            true,
+            "\n",
+        )
+        .unwrap();
+        assert_eq!(
+            replaced,
+            "a\n# Start flavours\nnew\n# End flavours\nb\n# start flavours\nnew\n# end flavours\n"
+        );
+    }
+
+    #[test]
+    fn leaves_other_blocks_alone() {
+        let content = "# Start flavours: a\nold a\n# End flavours: a\n# Start flavours: b\nold b\n# End flavours: b\n";
+        let replaced = replace_delimiter(
+            content,
+            "# start flavours: b",
+            "# end flavours: b",
+            "new b",
+            true,
+            "\n",
+        )
+        .unwrap();
+        assert_eq!(
+            replaced,
+            "# Start flavours: a\nold a\n# End flavours: a\n# Start flavours: b\nnew b\n# End flavours: b\n"
+        );
+    }
+
+    #[test]
+    fn missing_delimiters_are_errors() {
+        let start = "# start flavours";
+        let end = "# end flavours";
+        let error = replace_delimiter("a\n# Start flavours\nold\n", start, end, "new", true, "\n");
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "Couldn't find ending string."
+        );
+        let error = replace_delimiter("a\nb\n", start, end, "new", true, "\n");
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "Couldn't find starting string."
+        );
+    }
+
+    /// Item with default delimiters, as parsed from configuration
+    fn item() -> ConfigItem {
+        toml::from_str("file = \"a.conf\"\ntemplate = \"a\"").unwrap()
+    }
+
+    #[test]
+    fn inserts_into_empty_files() {
+        let block = "# Start flavours\n# End flavours\n";
+        assert_eq!(insert_block("", &item(), InsertMode::Append, "\n"), block);
+        assert_eq!(insert_block("", &item(), InsertMode::Prepend, "\n"), block);
+    }
+
+    #[test]
+    fn inserts_into_files_without_trailing_newline() {
+        assert_eq!(
+            insert_block("a\nb", &item(), InsertMode::Append, "\n"),
+            "a\nb\n# Start flavours\n# End flavours\n"
+        );
+        assert_eq!(
+            insert_block("a\nb", &item(), InsertMode::Prepend, "\n"),
+            "# Start flavours\n# End flavours\na\nb"
+        );
+    }
+
+    #[test]
+    fn only_inserts_missing_blocks() {
+        let content = "a\n  # START FLAVOURS\n# End flavours\n";
+        assert_eq!(
+            insert_block(content, &item(), InsertMode::Append, "\n"),
+            content
+        );
+        assert_eq!(insert_block("a\n", &item(), InsertMode::Error, "\n"), "a\n");
+    }
+
+    #[test]
+    fn indents_as_start_line() {
+        let content = "colors:\n  # Start flavours\n  old: 1\n  # End flavours\n";
+        let start = "# start flavours";
+        let end = "# end flavours";
+        let template = "a: 1\n\nb: 2";
+        assert_eq!(
+            replace_delimiter(content, start, end, template, true, "\n").unwrap(),
+            "colors:\n  # Start flavours\n  a: 1\n\n  b: 2\n  # End flavours\n"
+        );
+        assert_eq!(
+            replace_delimiter(content, start, end, template, false, "\n").unwrap(),
+            "colors:\n  # Start flavours\na: 1\n\nb: 2\n  # End flavours\n"
+        );
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let content = "a\r\n# Start flavours\r\nold\r\n# End flavours\r\nb\r\n";
+        let newline = line_ending(content);
+        assert_eq!(newline, "\r\n");
+        assert_eq!(line_ending("a\nb\r\n"), "\n");
+        assert_eq!(line_ending("a"), "\n");
+
+        let replaced = replace_delimiter(
+            content,
+            "# start flavours",
+            "# end flavours",
+            "x\r\ny",
+            true,
+            newline,
+        )
+        .unwrap();
+        assert_eq!(
+            replaced,
+            "a\r\n# Start flavours\r\nx\r\ny\r\n# End flavours\r\nb\r\n"
+        );
+        assert_eq!(
+            insert_block("a\r\n", &item(), InsertMode::Append, newline),
+            "a\r\n# Start flavours\r\n# End flavours\r\n"
+        );
+    }
+}
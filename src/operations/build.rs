@@ -1,26 +1,120 @@
 use anyhow::{anyhow, Context, Result};
+use base16_color_scheme::template::TemplateField;
 use base16_color_scheme::{Scheme, Template};
+use ramhorns::encoding::Encoder;
+use ramhorns::traits::ContentSequence;
+use ramhorns::{Content, Section};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path;
 
+/// Template content: a scheme, along with user defined variables
+///
+/// Scheme fields take precedence over variables with the same name.
+struct TemplateContent<'a> {
+    scheme: &'a Scheme,
+    variables: &'a BTreeMap<String, String>,
+}
+
+impl TemplateContent<'_> {
+    /// Whether a field is rendered from the scheme, instead of variables
+    fn is_scheme_field(&self, name: &str) -> bool {
+        match TemplateField::parse_field(name) {
+            TemplateField::UnparsableField => false,
+            TemplateField::ColorField(field) => self.scheme.color(field).is_some(),
+            _ => true,
+        }
+    }
+}
+
+impl Content for TemplateContent<'_> {
+    fn capacity_hint(&self, template: &Template) -> usize {
+        self.scheme.capacity_hint(template)
+    }
+
+    fn render_field_escaped<E: Encoder>(
+        &self,
+        hash: u64,
+        name: &str,
+        encoder: &mut E,
+    ) -> Result<bool, E::Error> {
+        if self.is_scheme_field(name) {
+            self.scheme.render_field_escaped(hash, name, encoder)
+        } else {
+            self.variables.render_field_escaped(hash, name, encoder)
+        }
+    }
+
+    fn render_field_unescaped<E: Encoder>(
+        &self,
+        hash: u64,
+        name: &str,
+        encoder: &mut E,
+    ) -> Result<bool, E::Error> {
+        if self.is_scheme_field(name) {
+            self.scheme.render_field_unescaped(hash, name, encoder)
+        } else {
+            self.variables.render_field_unescaped(hash, name, encoder)
+        }
+    }
+
+    fn render_field_section<C: ContentSequence, E: Encoder>(
+        &self,
+        hash: u64,
+        name: &str,
+        section: Section<C>,
+        encoder: &mut E,
+    ) -> Result<bool, E::Error> {
+        if self.is_scheme_field(name) {
+            self.scheme.render_field_section(hash, name, section, encoder)
+        } else {
+            self.variables.render_field_section(hash, name, section, encoder)
+        }
+    }
+
+    fn render_field_inverse<C: ContentSequence, E: Encoder>(
+        &self,
+        hash: u64,
+        name: &str,
+        section: Section<C>,
+        encoder: &mut E,
+    ) -> Result<bool, E::Error> {
+        if self.is_scheme_field(name) {
+            self.scheme.render_field_inverse(hash, name, section, encoder)
+        } else {
+            self.variables.render_field_inverse(hash, name, section, encoder)
+        }
+    }
+}
+
 /// Build a template
 ///
 /// Given template base and scheme, builds the template and returns it
 ///
 /// * `template_base` - Template base string
 /// * `scheme` - Scheme structure
-pub fn build_template(template_base: &str, scheme: &Scheme) -> Result<String> {
+/// * `variables` - Additional template variables
+pub fn build_template(
+    template_base: &str,
+    scheme: &Scheme,
+    variables: &BTreeMap<String, String>,
+) -> Result<String> {
     let template = Template::new(template_base)?;
-    Ok(template.render(scheme))
+    Ok(template.render(&TemplateContent { scheme, variables }))
 }
 
 /// Build function
 ///
 /// * `scheme_file` - Path to scheme file
 /// * `template_file` - Path to template
-pub fn build(scheme_file: &path::Path, template_file: &path::Path) -> Result<()> {
+/// * `variables` - Additional template variables
+pub fn build(
+    scheme_file: &path::Path,
+    template_file: &path::Path,
+    variables: &BTreeMap<String, String>,
+) -> Result<()> {
     //Read chosen scheme
-    let scheme_contents = &fs::read_to_string(&scheme_file)
+    let scheme_contents = &fs::read_to_string(scheme_file)
         .with_context(|| format!("Couldn't read scheme file at {:?}.", scheme_file))?;
 
     let slug = scheme_file
@@ -34,9 +128,7 @@ pub fn build(scheme_file: &path::Path, template_file: &path::Path) -> Result<()>
     let template_content = fs::read_to_string(template_file)
         .with_context(|| format!("Couldn't read template file at {:?}.", template_file))?;
 
-    let template = Template::new(template_content)?;
-
     //Template with correct colors
-    println!("{}", template.render(&scheme));
+    println!("{}", build_template(&template_content, &scheme, variables)?);
     Ok(())
 }
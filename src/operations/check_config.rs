@@ -0,0 +1,327 @@
+use anyhow::{anyhow, Context, Result};
+use base16_color_scheme::Template;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use std::fs;
+use std::path::Path;
+
+use crate::config::{
+    item_dependencies, Condition, Config, ConfigItem, ExtraSource, InsertMode, Profile,
+    TemplateSource,
+};
+use crate::find::{find_schemes, find_template};
+
+/// Field names a struct accepts, as known to its `Deserialize` implementation
+///
+/// Deserializes it from a stub that just records the field list it is asked for, so the known
+/// keys can't get out of sync with the configuration structs.
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct FieldsDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for FieldsDeserializer<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("only structs are supported"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("only the fields were needed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldsDeserializer(&mut fields));
+    fields
+}
+
+/// Closest known key to an unknown one, if any is similar enough
+///
+/// * `key` - Unknown key
+/// * `known` - Keys allowed where it was found
+fn suggest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (strsim::jaro_winkler(key, candidate), *candidate))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Report keys of a table that are not fields of `T`
+///
+/// * `value` - TOML table
+/// * `path` - Where the table is, for messages (empty for the top level)
+/// * `problems` - Found problems
+fn unknown_keys<'de, T: Deserialize<'de>>(
+    value: &toml::Value,
+    path: &str,
+    problems: &mut Vec<String>,
+) {
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => return,
+    };
+    let known = struct_fields::<T>();
+    for key in table.keys().filter(|key| !known.contains(&key.as_str())) {
+        let full_key = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", path, key)
+        };
+        match suggest(key, known) {
+            Some(suggestion) => problems.push(format!(
+                "unknown key '{}', did you mean '{}'?",
+                full_key, suggestion
+            )),
+            None => problems.push(format!("unknown key '{}'", full_key)),
+        }
+    }
+}
+
+/// Report unknown keys on an item, including its conditions
+///
+/// * `value` - TOML item table
+/// * `path` - Where the item is, for messages
+/// * `problems` - Found problems
+fn item_keys(value: &toml::Value, path: &str, problems: &mut Vec<String>) {
+    unknown_keys::<ConfigItem>(value, path, problems);
+    if let Some(when) = value.get("when") {
+        unknown_keys::<Condition>(when, &format!("{}.when", path), problems);
+    }
+}
+
+/// Report unknown keys on a configuration file, including nested tables
+///
+/// * `value` - Parsed configuration file
+/// * `problems` - Found problems
+fn check_keys(value: &toml::Value, problems: &mut Vec<String>) {
+    unknown_keys::<Config>(value, "", problems);
+
+    let array = |key: &str| {
+        value
+            .get(key)
+            .and_then(toml::Value::as_array)
+            .into_iter()
+            .flatten()
+    };
+    for key in ["items", "item"] {
+        for (index, item) in array(key).enumerate() {
+            item_keys(item, &format!("{}[{}]", key, index), problems);
+        }
+    }
+    for key in ["extra_scheme", "extra_template"] {
+        for (index, source) in array(key).enumerate() {
+            unknown_keys::<ExtraSource>(source, &format!("{}[{}]", key, index), problems);
+        }
+    }
+
+    let profiles = value.get("profiles").and_then(toml::Value::as_table);
+    for (name, profile) in profiles.into_iter().flatten() {
+        let path = format!("profiles.{}", name);
+        unknown_keys::<Profile>(profile, &path, problems);
+        let items = profile.get("items").and_then(toml::Value::as_array);
+        for (index, item) in items.into_iter().flatten().enumerate() {
+            item_keys(item, &format!("{}.items[{}]", path, index), problems);
+        }
+    }
+}
+
+/// Report problems with an item: missing templates, or target files that can't be replaced into
+///
+/// * `item` - Configured item
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+/// * `problems` - Found problems
+fn check_item(item: &ConfigItem, base_dir: &Path, config_dir: &Path, problems: &mut Vec<String>) {
+    let label = item.id.clone().unwrap_or_else(|| item.file.clone());
+
+    match item.template_source() {
+        Ok(TemplateSource::Named {
+            template,
+            subtemplate,
+        }) => {
+            // '{scheme}' depends on the applied scheme, and falls back to the default one
+            let subtemplate = match subtemplate {
+                "{scheme}" => "default",
+                subtemplate => subtemplate,
+            };
+            if let Err(error) = find_template(template, subtemplate, base_dir, config_dir) {
+                problems.push(format!(
+                    "item '{}': template {}/{} not found ({:#})",
+                    label, template, subtemplate, error
+                ));
+            }
+        }
+        Ok(TemplateSource::Inline(inline)) => {
+            if let Err(error) = Template::new(inline) {
+                problems.push(format!(
+                    "item '{}': invalid inline template ({})",
+                    label, error
+                ));
+            }
+        }
+        Err(error) => problems.push(format!("item '{}': {:#}", label, error)),
+    }
+
+    if let Err(error) = item.mode() {
+        problems.push(format!("item '{}': {:#}", label, error));
+    }
+
+    // Patterns using '{slug}' depend on the applied scheme, and fall back to it
+    if let Some(pattern) = item
+        .scheme
+        .as_deref()
+        .filter(|pattern| !pattern.contains("{slug}"))
+    {
+        match find_schemes(pattern, base_dir, config_dir) {
+            Ok(schemes) if !schemes.is_empty() => {}
+            Ok(_) => problems.push(format!("item '{}': no scheme matches '{}'", label, pattern)),
+            Err(error) => problems.push(format!(
+                "item '{}': invalid scheme pattern '{}' ({:#})",
+                label, pattern, error
+            )),
+        }
+    }
+
+    if item.rewrite.unwrap_or(false) {
+        return;
+    }
+    let file = match shellexpand::full(&item.file) {
+        Ok(file) => file.to_string(),
+        Err(error) => {
+            problems.push(format!(
+                "item '{}': couldn't expand file path ({})",
+                label, error
+            ));
+            return;
+        }
+    };
+    // Missing files and delimiters are added when applying
+    let inserting = !matches!(item.insert, None | Some(InsertMode::Error));
+    if !Path::new(&file).exists() {
+        // Conditional items are likely meant for other machines or sessions
+        if item.when.is_none() && !inserting {
+            problems.push(format!(
+                "item '{}': file {:?} doesn't exist (it's not rewritten, so it must have delimiters)",
+                label, file
+            ));
+        }
+        return;
+    }
+    let content = match fs::read_to_string(&file) {
+        Ok(content) => content,
+        Err(error) => {
+            problems.push(format!(
+                "item '{}': couldn't read {:?} ({})",
+                label, file, error
+            ));
+            return;
+        }
+    };
+
+    let (start, end) = (item.start_delimiter(), item.end_delimiter());
+    let mut lines = content.lines().map(|line| line.trim().to_lowercase());
+    if !lines.any(|line| line == start) {
+        if inserting {
+            return;
+        }
+        problems.push(format!(
+            "item '{}': {:?} doesn't contain the start delimiter '{}'",
+            label, file, start
+        ));
+    } else if !lines.any(|line| line == end) {
+        problems.push(format!(
+            "item '{}': {:?} doesn't contain the end delimiter '{}' after the start one",
+            label, file, end
+        ));
+    }
+}
+
+/// Config check subcommand
+///
+/// Prints every problem found, and fails if there are any.
+///
+/// * `config_path` - User configuration file path
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+pub fn check(config_path: &Path, base_dir: &Path, config_dir: &Path) -> Result<()> {
+    let layers = Config::load_layers(config_path)?;
+    let mut problems = Vec::new();
+
+    for layer in &layers {
+        let contents = fs::read_to_string(&layer.path)
+            .with_context(|| format!("Couldn't read configuration file {:?}.", layer.path))?;
+        let value: toml::Value = toml::from_str(&contents)
+            .with_context(|| format!("Couldn't parse configuration file {:?}.", layer.path))?;
+        let mut layer_problems = Vec::new();
+        check_keys(&value, &mut layer_problems);
+        for problem in layer_problems {
+            problems.push(format!("{}: {}", layer.path.display(), problem));
+        }
+    }
+
+    let config = Config::flatten(layers);
+
+    if let Some(shell) = &config.shell {
+        if !shell.contains("{}") {
+            problems.push(format!(
+                "shell '{}' doesn't contain the '{{}}' command placeholder",
+                shell
+            ));
+        } else if let Err(error) = shell_words::split(shell) {
+            problems.push(format!("shell '{}' can't be parsed ({})", shell, error));
+        }
+    }
+
+    let mut items = config.items.clone().unwrap_or_default();
+    items.extend(config.item.iter().flatten().cloned());
+    if let Err(error) = item_dependencies(&items) {
+        problems.push(format!("items: {:#}", error));
+    }
+
+    let profiles = config.profiles.clone().unwrap_or_default();
+    if let Some(name) = &config.default_profile {
+        if !profiles.contains_key(name) {
+            problems.push(format!("default_profile '{}' doesn't exist", name));
+        }
+    }
+    for name in profiles.keys() {
+        match config.items(Some(name)) {
+            Ok(items) => {
+                if let Err(error) = item_dependencies(&items) {
+                    problems.push(format!("profile '{}': {:#}", name, error));
+                }
+            }
+            Err(error) => problems.push(format!("{:#}", error)),
+        }
+    }
+
+    for item in items.iter().chain(config.profile_items()) {
+        check_item(item, base_dir, config_dir, &mut problems);
+    }
+
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    Err(anyhow!(
+        "Found {} problem{} on configuration",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" }
+    ))
+}
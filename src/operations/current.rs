@@ -5,7 +5,7 @@ use std::path::Path;
 /// Get current scheme
 ///
 /// * `dir` - flavours data directory
-fn get_current_scheme(dir: &Path) -> Result<String> {
+pub fn get_current_scheme(dir: &Path) -> Result<String> {
     // File that stores last used scheme
     let file_path = &dir.join("lastscheme");
     // Try to open it
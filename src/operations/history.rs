@@ -0,0 +1,44 @@
+use anyhow::{anyhow, Result};
+use chrono::{Local, TimeZone};
+use std::path::Path;
+
+use crate::history;
+
+/// Format a unix timestamp as local time
+///
+/// * `timestamp` - Milliseconds since the unix epoch
+pub fn format_timestamp(timestamp: u64) -> String {
+    match Local.timestamp_millis_opt(timestamp as i64).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// History subcommand
+///
+/// * `base_dir` - flavours data directory
+/// * `verbose` - Should we be verbose? Shows scheme files if true
+pub fn history(base_dir: &Path, verbose: bool) -> Result<()> {
+    let entries = history::read(base_dir)?;
+
+    if entries.is_empty() {
+        return Err(anyhow!("No schemes were applied yet. Try applying first."));
+    }
+
+    for (index, entry) in entries.iter().enumerate() {
+        print!(
+            "{:>3}  {}  {}  ({}, {})",
+            index + 1,
+            format_timestamp(entry.timestamp),
+            entry.slug,
+            entry.source,
+            entry.timestamp,
+        );
+        if verbose {
+            print!("  {}", entry.scheme_file.display());
+        }
+        println!();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::find::find_templates;
+use crate::operations::apply::{resolve_path, write_file};
+
+/// Structure for applications flavours knows how to set up
+struct KnownApp {
+    /// Application name, also used as the item id
+    name: &'static str,
+    /// Where its configuration usually is, the first one found is used
+    files: &'static [&'static str],
+    /// Template for it
+    template: &'static str,
+    /// Subtemplates to use, in order of preference (otherwise `default`, or the first one found)
+    subtemplates: &'static [&'static str],
+    /// How its configuration starts and ends comments
+    comment: (&'static str, &'static str),
+    /// Colors have to be set before anything else, so delimiters go on the start of the file
+    prepend: bool,
+    /// Command to reload it
+    hook: Option<&'static str>,
+    /// Whether the hook is quick to run
+    light: bool,
+}
+
+const HASH: (&str, &str) = ("#", "");
+const CSS: (&str, &str) = ("/*", " */");
+
+/// Applications `init` looks for
+const KNOWN_APPS: &[KnownApp] = &[
+    KnownApp {
+        name: "alacritty",
+        files: &[
+            "~/.config/alacritty/alacritty.toml",
+            "~/.config/alacritty/alacritty.yml",
+        ],
+        template: "alacritty",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "kitty",
+        files: &["~/.config/kitty/kitty.conf"],
+        template: "kitty",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "foot",
+        files: &["~/.config/foot/foot.ini"],
+        template: "foot",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "sway",
+        files: &["~/.config/sway/config"],
+        template: "sway",
+        subtemplates: &["colors"],
+        comment: HASH,
+        prepend: false,
+        hook: Some("swaymsg reload"),
+        light: false,
+    },
+    KnownApp {
+        name: "i3",
+        files: &["~/.config/i3/config", "~/.i3/config"],
+        template: "i3",
+        subtemplates: &["colors"],
+        comment: HASH,
+        prepend: false,
+        hook: Some("i3-msg reload"),
+        light: false,
+    },
+    KnownApp {
+        name: "waybar",
+        files: &["~/.config/waybar/style.css"],
+        template: "waybar",
+        subtemplates: &[],
+        comment: CSS,
+        prepend: true,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "rofi",
+        files: &["~/.config/rofi/config.rasi"],
+        template: "rofi",
+        subtemplates: &["colors"],
+        comment: CSS,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "dunst",
+        files: &["~/.config/dunst/dunstrc"],
+        template: "dunst",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "zathura",
+        files: &["~/.config/zathura/zathurarc"],
+        template: "zathura",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+    KnownApp {
+        name: "tmux",
+        files: &["~/.config/tmux/tmux.conf", "~/.tmux.conf"],
+        template: "tmux",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: Some("tmux source-file \"$FLAVOURS_ITEM_FILE\""),
+        light: true,
+    },
+    KnownApp {
+        name: "xresources",
+        files: &["~/.Xresources"],
+        template: "xresources",
+        subtemplates: &[],
+        comment: ("!", ""),
+        prepend: false,
+        hook: Some("xrdb -merge \"$FLAVOURS_ITEM_FILE\""),
+        light: true,
+    },
+    KnownApp {
+        name: "qutebrowser",
+        files: &["~/.config/qutebrowser/config.py"],
+        template: "qutebrowser",
+        subtemplates: &[],
+        comment: HASH,
+        prepend: false,
+        hook: None,
+        light: true,
+    },
+];
+
+/// Structure for a detected application
+struct Detected<'a> {
+    app: &'a KnownApp,
+    /// Configuration file, as written on flavours configuration
+    file: &'static str,
+    /// Configuration file, expanded
+    path: PathBuf,
+    /// Installed subtemplate to use, if the template is installed at all
+    subtemplate: Option<String>,
+}
+
+impl KnownApp {
+    /// Start and end delimiters, using the application's comment syntax
+    fn delimiters(&self) -> (String, String) {
+        let (open, close) = self.comment;
+        (
+            format!("{} Start flavours{}", open, close),
+            format!("{} End flavours{}", open, close),
+        )
+    }
+
+    /// Pick one of the installed subtemplates for this application
+    ///
+    /// * `base_dir` - flavours data directory
+    /// * `config_dir` - flavours config directory
+    fn subtemplate(&self, base_dir: &Path, config_dir: &Path) -> Result<Option<String>> {
+        let mut installed: Vec<String> =
+            find_templates(&format!("{}/*", self.template), base_dir, config_dir)?
+                .iter()
+                .filter_map(|path| path.file_stem())
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .collect();
+        installed.sort();
+        installed.dedup();
+
+        let preferred = self
+            .subtemplates
+            .iter()
+            .chain(&["default"])
+            .find(|subtemplate| installed.iter().any(|name| name == *subtemplate));
+        Ok(match preferred {
+            Some(subtemplate) => Some(subtemplate.to_string()),
+            None => installed.into_iter().next(),
+        })
+    }
+}
+
+/// Quote a string as a TOML value
+///
+/// * `value` - String to quote
+fn quote(value: &str) -> String {
+    toml::Value::String(value.into()).to_string()
+}
+
+/// Write the item for a detected application, commented out if its template isn't installed
+///
+/// * `detected` - Detected application
+fn item(detected: &Detected) -> String {
+    let app = detected.app;
+    let mut lines = vec![
+        String::from("[[items]]"),
+        format!("id = {}", quote(app.name)),
+        format!("file = {}", quote(detected.file)),
+        format!("template = {}", quote(app.template)),
+    ];
+    match &detected.subtemplate {
+        Some(subtemplate) if subtemplate != "default" => {
+            lines.push(format!("subtemplate = {}", quote(subtemplate)))
+        }
+        _ => {}
+    }
+    if app.comment != HASH {
+        let (start, end) = app.delimiters();
+        lines.push(format!("start = {}", quote(&start)));
+        lines.push(format!("end = {}", quote(&end)));
+    }
+    if let Some(hook) = app.hook {
+        lines.push(format!("hook = {}", quote(hook)));
+        if !app.light {
+            lines.push(String::from("light = false"));
+        }
+    }
+
+    let mut item = format!("# {}\n", app.name);
+    match detected.subtemplate {
+        Some(_) => {
+            for line in lines {
+                item.push_str(&format!("{}\n", line));
+            }
+        }
+        None => {
+            item.push_str(&format!(
+                "# The '{}' template isn't installed. Run 'flavours update templates', or add it to your templates directory\n",
+                app.template
+            ));
+            for line in lines {
+                item.push_str(&format!("# {}\n", line));
+            }
+        }
+    }
+    item
+}
+
+/// Ask a yes or no question, defaulting to no
+///
+/// * `question` - What to ask
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Add start and end delimiters to an application configuration file, if it doesn't have them
+///
+/// * `detected` - Detected application
+fn add_delimiters(detected: &Detected) -> Result<()> {
+    let (start, end) = detected.app.delimiters();
+    let content = fs::read_to_string(&detected.path)
+        .with_context(|| format!("Couldn't read {:?}", detected.path))?;
+    let has_start = content
+        .lines()
+        .any(|line| line.trim().to_lowercase() == start.to_lowercase());
+    if has_start || !confirm(&format!("Add flavours delimiters to {:?}?", detected.path))? {
+        return Ok(());
+    }
+
+    let block = format!("{}\n{}\n", start, end);
+    let content = if detected.app.prepend {
+        format!("{}\n{}", block, content)
+    } else if content.is_empty() || content.ends_with('\n') {
+        format!("{}\n{}", content, block)
+    } else {
+        format!("{}\n\n{}", content, block)
+    };
+    // Write through symlinks (e.g. dotfiles managers), instead of replacing them
+    write_file(
+        &resolve_path(&detected.path.to_string_lossy()),
+        content.as_bytes(),
+    )
+}
+
+/// Init subcommand
+///
+/// Looks for configuration files of known applications, and writes a configuration with an item
+/// for each one found.
+///
+/// * `config_path` - Where to write the configuration
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+/// * `force` - Overwrite an existing configuration
+/// * `verbose` - Should we be verbose?
+pub fn init(
+    config_path: &Path,
+    base_dir: &Path,
+    config_dir: &Path,
+    force: bool,
+    verbose: bool,
+) -> Result<()> {
+    if config_path.exists() && !force {
+        return Err(anyhow!(
+            "Configuration file {:?} already exists, use --force to overwrite it",
+            config_path
+        ));
+    }
+
+    let mut detected = Vec::new();
+    for app in KNOWN_APPS {
+        let mut found = None;
+        for file in app.files {
+            let path = PathBuf::from(shellexpand::full(file)?.to_string());
+            if path.is_file() {
+                found = Some((*file, path));
+                break;
+            }
+        }
+        match found {
+            Some((file, path)) => detected.push(Detected {
+                app,
+                file,
+                path,
+                subtemplate: app.subtemplate(base_dir, config_dir)?,
+            }),
+            None if verbose => println!("Didn't find {}", app.name),
+            None => {}
+        }
+    }
+
+    let mut config = String::from(
+        "# Configuration for flavours, generated by 'flavours init'\n\
+         # https://github.com/Misterio77/flavours\n\
+         #\n\
+         # Each [[items]] section is an application flavours applies schemes to\n\
+         # Check flavours repository for more information and examples\n",
+    );
+    if detected.is_empty() {
+        config.push_str("\n# No known applications were found, add your own [[items]] here\n");
+    }
+    for app in &detected {
+        config.push('\n');
+        config.push_str(&item(app));
+    }
+
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Couldn't create directory {:?}", dir))?;
+    }
+    fs::write(config_path, &config)
+        .with_context(|| format!("Couldn't write configuration file {:?}", config_path))?;
+    println!("Wrote configuration to {:?}", config_path);
+
+    for app in &detected {
+        match app.subtemplate {
+            Some(_) => println!("Added {} ({})", app.app.name, app.file),
+            None => println!(
+                "Added {} ({}) commented out, as the '{}' template isn't installed",
+                app.app.name, app.file, app.app.template
+            ),
+        }
+    }
+
+    // Items that replace lines need delimiters on their files
+    for app in detected.iter().filter(|app| app.subtemplate.is_some()) {
+        add_delimiters(app)?;
+    }
+    Ok(())
+}
@@ -1,8 +1,15 @@
 pub mod apply;
 pub mod build;
+pub mod check_config;
 pub mod current;
 pub mod generate;
+pub mod history;
 pub mod info;
+pub mod init;
 pub mod list;
 pub mod list_templates;
+pub mod restore;
+pub mod show_config;
+pub mod undo;
 pub mod update;
+pub mod watch;
@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::backup;
+use crate::operations::apply::{resolve_path, write_file};
+use crate::operations::history::format_timestamp;
+
+/// Restore a single file from backup
+///
+/// * `base_dir` - flavours data directory
+/// * `file` - File to restore
+/// * `at` - Restore the latest backup not newer than this timestamp
+/// * `verbose` - Should we be verbose?
+fn restore_file(base_dir: &Path, file: &Path, at: Option<u64>, verbose: bool) -> Result<()> {
+    let (timestamp, backup_file) = backup::find(base_dir, file, at)?;
+    let content =
+        fs::read(&backup_file).with_context(|| format!("Couldn't read backup {:?}", backup_file))?;
+    write_file(file, &content).with_context(|| format!("Couldn't write to file {:?}", file))?;
+    if verbose {
+        println!(
+            "Restored {:?} from backup taken at {} ({})",
+            file,
+            format_timestamp(timestamp),
+            timestamp
+        );
+    }
+    Ok(())
+}
+
+/// Restore subcommand
+///
+/// * `file` - File to restore, restores all backed up files if omitted
+/// * `at` - Restore the latest backup not newer than this timestamp
+/// * `list` - Only list available backups
+/// * `base_dir` - flavours data directory
+/// * `verbose` - Should we be verbose?
+pub fn restore(
+    file: Option<&str>,
+    at: Option<u64>,
+    list: bool,
+    base_dir: &Path,
+    verbose: bool,
+) -> Result<()> {
+    if list {
+        let files = backup::list(base_dir)?;
+        if files.is_empty() {
+            return Err(anyhow!("No backups found"));
+        }
+        for (file, timestamps) in files {
+            println!("{}", file.display());
+            for timestamp in timestamps {
+                println!("  {}  ({})", format_timestamp(timestamp), timestamp);
+            }
+        }
+        return Ok(());
+    }
+
+    match file {
+        Some(file) => {
+            let file = shellexpand::full(file)?.to_string();
+            restore_file(base_dir, &resolve_path(&file), at, verbose)
+        }
+        None => {
+            let files = backup::list(base_dir)?;
+            if files.is_empty() {
+                return Err(anyhow!("No backups found"));
+            }
+            let mut restored = 0;
+            for (file, timestamps) in files {
+                // Files backed up only after the given timestamp are left alone
+                if at.map_or(true, |at| timestamps[0] <= at) {
+                    restore_file(base_dir, &file, at, verbose)?;
+                    restored += 1;
+                }
+            }
+            if restored == 0 {
+                return Err(anyhow!("No backups taken at or before the given timestamp"));
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::config::Config;
+
+/// Serialize a configuration as TOML
+///
+/// * `config` - Configuration to serialize
+fn to_toml(config: &Config) -> Result<String> {
+    // Going through a toml value takes care of putting tables after plain values
+    let value = toml::Value::try_from(config).context("Couldn't serialize configuration")?;
+    toml::to_string(&value).context("Couldn't serialize configuration")
+}
+
+/// Config show subcommand
+///
+/// * `config_path` - User configuration file path
+/// * `layers` - Also print each configuration layer, before the effective configuration
+pub fn show(config_path: &Path, layers: bool) -> Result<()> {
+    let all_layers = Config::load_layers(config_path)?;
+
+    if layers {
+        for layer in &all_layers {
+            let kind = if layer.system { "System" } else { "User" };
+            println!("# {} layer: {:?}", kind, layer.path);
+            println!("{}", to_toml(&layer.config)?);
+        }
+        println!("# Effective configuration");
+    }
+
+    print!("{}", to_toml(&Config::flatten(all_layers))?);
+    Ok(())
+}
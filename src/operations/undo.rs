@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+
+use crate::history;
+use crate::operations::apply::{apply, ApplyOptions, SchemeSource};
+
+/// Undo subcommand
+///
+/// Applies the scheme used before the last one, and drops the last one from history
+///
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+/// * `config_path` - flavours configuration path
+/// * `options` - Apply options
+pub fn undo(
+    base_dir: &Path,
+    config_dir: &Path,
+    config_path: &Path,
+    options: &ApplyOptions,
+) -> Result<()> {
+    let entries = history::read(base_dir)?;
+
+    let previous = match entries.len() {
+        0 | 1 => return Err(anyhow!("There's no previously applied scheme to go back to.")),
+        len => &entries[len - 2],
+    };
+
+    if options.verbose {
+        println!(
+            "Going back to {}, applied at {}",
+            previous.slug,
+            super::history::format_timestamp(previous.timestamp)
+        );
+    }
+
+    apply(
+        SchemeSource::File(previous.scheme_file.clone()),
+        base_dir,
+        config_dir,
+        config_path,
+        options,
+    )?;
+
+    // Keep the history as is when nothing was applied
+    if !options.dry_run {
+        history::pop(base_dir)?;
+    }
+
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use std::env::set_var;
-use std::fs::{create_dir_all, remove_dir_all, write, File, read_to_string, OpenOptions};
+use std::fs::{create_dir_all, remove_dir_all, File, OpenOptions};
 use std::io::{self, BufRead, BufReader, prelude::*};
 use std::path::Path;
 use std::process::Command;
@@ -228,32 +228,7 @@ fn update_lists(
         println!("Updating sources list from sources.yaml")
     }
 
-    // This section dealing with obtaining the config struct is copied directly from apply.rs
-    //Check if config file exists
-    if !config_path.exists() {
-        eprintln!("Config {:?} doesn't exist, creating", config_path);
-        let default_content = match read_to_string("/etc/flavours.conf") {
-            Ok(content) => content,
-            Err(_) => String::from(""),
-        };
-        let config_path_parent = config_path
-            .parent()
-            .with_context(|| format!("Couldn't get parent directory of {:?}", config_path))?;
-
-        create_dir_all(config_path_parent).with_context(|| {
-            format!(
-                "Couldn't create configuration file parent directory {:?}",
-                config_path_parent
-            )
-        })?;
-        write(config_path, default_content)
-            .with_context(|| format!("Couldn't create configuration file at {:?}", config_path))?;
-    }
-
-    let config_contents = read_to_string(config_path)
-        .with_context(|| format!("Couldn't read configuration file {:?}.", config_path))?;
-
-    let config = Config::read(&config_contents, config_path)?;
+    let config = Config::load(config_path)?;
 
     // Get schemes and templates repository from file
     let (schemes_source, templates_source) = get_sources(
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+
+use crate::config::{Config, ConfigItem};
+use crate::find::find_schemes;
+use crate::history;
+use crate::operations::apply::{apply, ApplyOptions, SchemeSource};
+use crate::operations::current::get_current_scheme;
+
+/// How long to wait for more changes before applying, so a burst of events (e.g. an editor
+/// saving through a temporary file) only applies once
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Files and directories whose changes are acted upon
+struct Watched {
+    /// Configuration files (every layer)
+    config_files: Vec<PathBuf>,
+    /// Currently applied scheme file
+    scheme_file: PathBuf,
+    /// History file, which changes when a scheme is applied somewhere else (as nothing is
+    /// applied then, it's only watched so the new scheme file is watched as well)
+    history_file: PathBuf,
+    /// Templates directory on the config dir
+    templates_dir: PathBuf,
+    /// Schemes directory on the config dir
+    schemes_dir: PathBuf,
+}
+
+/// What a batch of changes affects
+#[derive(Default)]
+struct Changes {
+    /// Configuration or the applied scheme changed, so every item is affected
+    all: bool,
+    /// Names of templates that changed
+    templates: BTreeSet<String>,
+    /// A scheme on the config dir changed, affecting items that pick their own scheme
+    schemes: bool,
+}
+
+/// Currently applied scheme file
+///
+/// Taken from the history, or found by the last applied scheme name if there's no history yet
+/// (e.g. schemes applied before it was kept).
+///
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+fn current_scheme_file(base_dir: &Path, config_dir: &Path) -> Result<PathBuf> {
+    if let Some(entry) = history::read(base_dir)?.pop() {
+        return Ok(entry.scheme_file);
+    }
+    let slug = get_current_scheme(base_dir)?;
+    let mut schemes = find_schemes(&Pattern::escape(&slug), base_dir, config_dir)?;
+    schemes.sort();
+    schemes.into_iter().next().ok_or_else(|| {
+        anyhow!(
+            "Couldn't find the applied scheme '{}'. Try applying again.",
+            slug
+        )
+    })
+}
+
+/// Canonical version of a path, resolving its parent only (so it works for files that were just
+/// removed or replaced)
+///
+/// * `path` - File path
+fn canonical(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+impl Watched {
+    /// Find what to watch, from the configuration and history
+    ///
+    /// * `base_dir` - flavours data directory
+    /// * `config_dir` - flavours config directory
+    /// * `config_path` - flavours configuration path
+    fn new(base_dir: &Path, config_dir: &Path, config_path: &Path) -> Result<Watched> {
+        let scheme_file = current_scheme_file(base_dir, config_dir)?;
+
+        // A broken configuration is still watched, so it's applied again once it's fixed
+        let mut config_files = vec![canonical(config_path)];
+        if let Ok(layers) = Config::load_layers(config_path) {
+            config_files.extend(layers.iter().map(|layer| canonical(&layer.path)));
+        }
+        config_files.sort();
+        config_files.dedup();
+
+        let canonical_dir = |dir: PathBuf| dir.canonicalize().unwrap_or(dir);
+        Ok(Watched {
+            config_files,
+            scheme_file: canonical(&scheme_file),
+            history_file: canonical(&base_dir.join("history")),
+            templates_dir: canonical_dir(config_dir.join("templates")),
+            schemes_dir: canonical_dir(config_dir.join("schemes")),
+        })
+    }
+
+    /// Directories to watch, and whether to watch them recursively
+    ///
+    /// Files are watched through their directories, as editors often replace them instead of
+    /// writing to them.
+    fn dirs(&self) -> BTreeMap<PathBuf, RecursiveMode> {
+        let mut dirs = BTreeMap::new();
+        let files = self
+            .config_files
+            .iter()
+            .chain([&self.scheme_file, &self.history_file]);
+        for file in files {
+            if let Some(dir) = file.parent().filter(|dir| dir.is_dir()) {
+                dirs.insert(dir.to_path_buf(), RecursiveMode::NonRecursive);
+            }
+        }
+        for dir in [&self.templates_dir, &self.schemes_dir] {
+            if dir.is_dir() {
+                dirs.insert(dir.clone(), RecursiveMode::Recursive);
+            }
+        }
+        dirs
+    }
+
+    /// Record what a changed path affects, ignoring anything that isn't watched
+    ///
+    /// * `path` - Changed path
+    /// * `changes` - Changes found so far
+    fn classify(&self, path: &Path, changes: &mut Changes) {
+        let path = canonical(path);
+        if self.config_files.contains(&path) || path == self.scheme_file {
+            changes.all = true;
+        } else if let Ok(relative) = path.strip_prefix(&self.templates_dir) {
+            // Templates are laid out as 'templates/NAME/templates/SUBTEMPLATE.mustache'
+            if let Some(name) = relative.iter().next() {
+                changes
+                    .templates
+                    .insert(name.to_string_lossy().into_owned());
+            }
+        } else if path.starts_with(&self.schemes_dir) {
+            changes.schemes = true;
+        }
+    }
+}
+
+/// Patterns selecting the items affected by some changes, for apply's `only` option
+///
+/// * `items` - Configured items
+/// * `changes` - What changed
+fn affected(items: &[ConfigItem], changes: &Changes) -> Vec<String> {
+    items
+        .iter()
+        .filter(|item| {
+            let template = item
+                .template
+                .as_ref()
+                .filter(|template| changes.templates.contains(*template));
+            template.is_some() || (changes.schemes && item.scheme.is_some())
+        })
+        .map(|item| match &item.id {
+            Some(id) => Pattern::escape(id),
+            None => Pattern::escape(&item.file),
+        })
+        .collect()
+}
+
+/// Watch subcommand
+///
+/// Applies the current scheme again whenever the configuration, a template or scheme on the
+/// config directory, or the current scheme file changes. Only items affected by the change are
+/// applied, and the history is left untouched. Runs until interrupted.
+///
+/// * `base_dir` - flavours data directory
+/// * `config_dir` - flavours config directory
+/// * `config_path` - flavours configuration path
+/// * `options` - Apply options (light mode, verbosity, profile)
+pub fn watch(
+    base_dir: &Path,
+    config_dir: &Path,
+    config_path: &Path,
+    options: &ApplyOptions,
+) -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher: RecommendedWatcher =
+        notify::recommended_watcher(sender).context("Couldn't start watching files")?;
+    let mut watching: BTreeMap<PathBuf, RecursiveMode> = BTreeMap::new();
+
+    println!("Watching for changes, press Ctrl-C to stop");
+    loop {
+        // Configuration and history may have changed, so look for what to watch every time
+        let watched = Watched::new(base_dir, config_dir, config_path)?;
+        let dirs = watched.dirs();
+        for (dir, mode) in &watching {
+            if dirs.get(dir) != Some(mode) {
+                let _ = watcher.unwatch(dir);
+            }
+        }
+        for (dir, mode) in &dirs {
+            if watching.get(dir) != Some(mode) {
+                watcher
+                    .watch(dir, *mode)
+                    .with_context(|| format!("Couldn't watch {:?}", dir))?;
+                if options.verbose {
+                    println!("Watching {:?}", dir);
+                }
+            }
+        }
+        watching = dirs;
+
+        // Wait for a change, then for things to settle down
+        let mut changes = Changes::default();
+        let mut event = receiver.recv().context("Stopped receiving file changes")?;
+        loop {
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    for path in &event.paths {
+                        watched.classify(path, &mut changes);
+                    }
+                }
+                Ok(_) => {}
+                Err(error) => eprintln!("Error watching files: {}", error),
+            }
+            event = match receiver.recv_timeout(DEBOUNCE) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow!("Stopped receiving file changes"))
+                }
+            };
+        }
+
+        let only = if changes.all {
+            Vec::new()
+        } else if changes.templates.is_empty() && !changes.schemes {
+            continue;
+        } else {
+            // Errors on the configuration are reported by apply, when everything is applied
+            let items = match Config::load(config_path)
+                .and_then(|config| config.items(options.profile.as_deref()))
+            {
+                Ok(items) => items,
+                Err(error) => {
+                    eprintln!("Error: {:?}", error);
+                    continue;
+                }
+            };
+            let only = affected(&items, &changes);
+            if only.is_empty() {
+                continue;
+            }
+            only
+        };
+
+        let description = match only.len() {
+            0 => String::from("every item"),
+            _ => only.join(", "),
+        };
+        // Backups of what was there before watching would soon be rotated out
+        let options = ApplyOptions {
+            only,
+            skip_backups: true,
+            ..options.clone()
+        };
+        match apply(
+            SchemeSource::File(watched.scheme_file.clone()),
+            base_dir,
+            config_dir,
+            config_path,
+            &options,
+        ) {
+            Ok(()) => println!("Applied to {}", description),
+            Err(error) => eprintln!("Error: {:?}", error),
+        }
+    }
+}